@@ -1,73 +1,451 @@
-const VERSION: u8 = 0x01;
-const HEADER: [u8; 4] = [0xf0, 0x00, 0x70, VERSION];
-const FOOTER: [u8; 1] = [0xf7];
+use std::fmt;
+
+use error::{CommandError, DecodeError};
+use protocol::{Encoding, Protocol};
+use target::Target;
+
+pub const VERSION: u8 = 0x01;
+pub const HEADER: [u8; 4] = [0xf0, 0x00, 0x70, VERSION];
+pub const FOOTER: [u8; 1] = [0xf7];
+
+pub const COMMAND_PING: u8 = 0x10;
+pub const COMMAND_WRITE: u8 = 0x11;
+pub const COMMAND_READ: u8 = 0x12;
+pub const COMMAND_VERIFY: u8 = 0x13;
+pub const COMMAND_QUIT: u8 = 0x14;
+pub const COMMAND_VERIFY_RANGE: u8 = 0x15;
 
 pub trait Command {
+    // Encoded for VERSION 0x01, which every bootloader understands.
     fn to_sysex(&self) -> Vec<u8> {
+        self.to_sysex_with(&Protocol::default())
+    }
+
+    fn to_sysex_with(&self, protocol: &Protocol) -> Vec<u8> {
         let mut payload = self.payload();
-        let checksum = payload.iter().fold(0, |acc, val| acc ^ val);
-        payload.push(checksum);
+        let checksum = protocol.integrity.checksum(&payload);
+        payload.extend(checksum.to_bytes());
         let mut message = Vec::new();
-        message.extend(HEADER.to_vec());
-        message.extend(self.to_nibbles(payload));
+        message.extend(protocol.header().to_vec());
+        message.extend(protocol.encoding.encode(&payload));
         message.extend(FOOTER.to_vec());
         message
     }
 
     fn to_nibbles(&self, vec: Vec<u8>) -> Vec<u8> {
-        vec.iter()
-            .flat_map(|byte| vec![byte >> 4, byte & 0x0f])
-            .collect()
+        Encoding::Nibbles.encode(&vec)
     }
 
     fn payload(&self) -> Vec<u8>;
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ping {}
 
 impl Command for Ping {
     fn payload(&self) -> Vec<u8> {
-        vec![0x10]
+        vec![COMMAND_PING]
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Write {
     page_no: u8,
     page_data: Vec<u8>,
 }
 
+impl Write {
+    pub fn new(target: &Target, page_no: usize, page_data: Vec<u8>) -> Result<Write, CommandError> {
+        if page_data.len() != target.page_size {
+            return Err(CommandError::InvalidPageSize {
+                expected: target.page_size,
+                actual: page_data.len(),
+            });
+        }
+        Ok(Write {
+            page_no: check_page_no(target, page_no)?,
+            page_data,
+        })
+    }
+
+    pub fn page_no(&self) -> u8 {
+        self.page_no
+    }
+
+    pub fn page_data(&self) -> &[u8] {
+        &self.page_data
+    }
+}
+
 impl Command for Write {
     fn payload(&self) -> Vec<u8> {
-        let mut payload = vec![0x11, self.page_no];
+        let mut payload = vec![COMMAND_WRITE, self.page_no];
         payload.extend(self.page_data.iter());
         payload
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Read {
     page_no: u8,
 }
 
+impl Read {
+    pub fn new(target: &Target, page_no: usize) -> Result<Read, CommandError> {
+        Ok(Read {
+            page_no: check_page_no(target, page_no)?,
+        })
+    }
+
+    pub fn page_no(&self) -> u8 {
+        self.page_no
+    }
+}
+
 impl Command for Read {
     fn payload(&self) -> Vec<u8> {
-        vec![0x12, self.page_no]
+        vec![COMMAND_READ, self.page_no]
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Verify {
     page_no: u8,
 }
 
+impl Verify {
+    pub fn new(target: &Target, page_no: usize) -> Result<Verify, CommandError> {
+        Ok(Verify {
+            page_no: check_page_no(target, page_no)?,
+        })
+    }
+
+    pub fn page_no(&self) -> u8 {
+        self.page_no
+    }
+}
+
 impl Command for Verify {
     fn payload(&self) -> Vec<u8> {
-        vec![0x13, self.page_no]
+        vec![COMMAND_VERIFY, self.page_no]
     }
 }
 
+// Asks for one checksum over a run of pages, so a whole application can be
+// verified in a single round trip. Only protocols with `verify_range` know it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyRange {
+    first_page: u8,
+    last_page: u8,
+}
+
+impl VerifyRange {
+    pub fn new(
+        target: &Target,
+        first_page: usize,
+        last_page: usize,
+    ) -> Result<VerifyRange, CommandError> {
+        if last_page < first_page {
+            return Err(CommandError::InvalidPageRange {
+                first_page,
+                last_page,
+            });
+        }
+        Ok(VerifyRange {
+            first_page: check_page_no(target, first_page)?,
+            last_page: check_page_no(target, last_page)?,
+        })
+    }
+
+    pub fn first_page(&self) -> u8 {
+        self.first_page
+    }
+
+    pub fn last_page(&self) -> u8 {
+        self.last_page
+    }
+}
+
+impl Command for VerifyRange {
+    fn payload(&self) -> Vec<u8> {
+        vec![COMMAND_VERIFY_RANGE, self.first_page, self.last_page]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Quit {}
 
 impl Command for Quit {
     fn payload(&self) -> Vec<u8> {
-        vec![0x14]
+        vec![COMMAND_QUIT]
+    }
+}
+
+fn check_page_no(target: &Target, page_no: usize) -> Result<u8, CommandError> {
+    if page_no >= target.num_pages() {
+        return Err(CommandError::InvalidPageNumber {
+            page_no,
+            num_pages: target.num_pages(),
+        });
+    }
+    Ok(page_no as u8)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Ping(Ping),
+    Write(Write),
+    Read(Read),
+    Verify(Verify),
+    VerifyRange(VerifyRange),
+    Quit(Quit),
+}
+
+impl Request {
+    pub fn from_sysex(message: &[u8], target: &Target) -> Result<Request, DecodeError> {
+        let payload = from_sysex(message)?;
+        let (&command, params) = payload.split_first().ok_or(DecodeError::InvalidFormat)?;
+        let expected_size = match command {
+            COMMAND_PING | COMMAND_QUIT => 0,
+            COMMAND_WRITE => target.page_size + 1,
+            COMMAND_READ | COMMAND_VERIFY => 1,
+            COMMAND_VERIFY_RANGE => 2,
+            _ => return Err(DecodeError::UnknownCommand(command)),
+        };
+        if params.len() != expected_size {
+            return Err(DecodeError::InvalidPayloadSize);
+        }
+
+        Ok(match command {
+            COMMAND_PING => Request::Ping(Ping {}),
+            COMMAND_WRITE => Request::Write(Write {
+                page_no: params[0],
+                page_data: params[1..].to_vec(),
+            }),
+            COMMAND_READ => Request::Read(Read { page_no: params[0] }),
+            COMMAND_VERIFY => Request::Verify(Verify { page_no: params[0] }),
+            COMMAND_VERIFY_RANGE => Request::VerifyRange(VerifyRange {
+                first_page: params[0],
+                last_page: params[1],
+            }),
+            _ => Request::Quit(Quit {}),
+        })
+    }
+}
+
+impl Command for Request {
+    fn payload(&self) -> Vec<u8> {
+        match *self {
+            Request::Ping(ref ping) => ping.payload(),
+            Request::Write(ref write) => write.payload(),
+            Request::Read(ref read) => read.payload(),
+            Request::Verify(ref verify) => verify.payload(),
+            Request::VerifyRange(ref verify_range) => verify_range.payload(),
+            Request::Quit(ref quit) => quit.payload(),
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Request::Ping(_) => write!(f, "ping"),
+            Request::Write(ref write) => write!(
+                f,
+                "write page {} ({} bytes, checksum 0x{:02x})",
+                write.page_no,
+                write.page_data.len(),
+                checksum(&write.page_data)
+            ),
+            Request::Read(ref read) => write!(f, "read page {}", read.page_no),
+            Request::Verify(ref verify) => write!(f, "verify page {}", verify.page_no),
+            Request::VerifyRange(ref verify_range) => write!(
+                f,
+                "verify pages {} to {}",
+                verify_range.first_page, verify_range.last_page
+            ),
+            Request::Quit(_) => write!(f, "quit"),
+        }
+    }
+}
+
+pub fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |acc, val| acc ^ val)
+}
+
+pub fn crc16(bytes: &[u8]) -> u16 {
+    // CRC-16/CCITT with the 0x1021 polynomial and 0xffff initial value, as
+    // avr-libc's _crc_xmodem_update computes it when started from 0xffff.
+    bytes.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// Decodes a message of any protocol version this host knows.
+pub fn from_sysex(message: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let protocol = protocol_of(message)?;
+
+    let data = match message[HEADER.len()..].split_last() {
+        Some((&byte, data)) if [byte] == FOOTER => data,
+        _ => return Err(DecodeError::MissingFooter),
+    };
+
+    let mut payload = protocol.encoding.decode(data)?;
+    let size = protocol.integrity.size();
+    if payload.len() <= size {
+        return Err(DecodeError::InvalidFormat);
+    }
+    let checksum = payload.split_off(payload.len() - size);
+    if protocol.integrity.checksum(&payload).to_bytes() != checksum {
+        return Err(DecodeError::InvalidChecksum);
+    }
+    Ok(payload)
+}
+
+pub fn protocol_of(message: &[u8]) -> Result<&'static Protocol, DecodeError> {
+    let version_index = HEADER.len() - 1;
+    if message.len() < HEADER.len() || message[..version_index] != HEADER[..version_index] {
+        return Err(DecodeError::HeaderMismatch);
+    }
+    let version = message[version_index];
+    Protocol::by_version(version).ok_or(DecodeError::VersionMismatch(version))
+}
+
+pub fn from_nibbles(nibbles: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    if nibbles.len() & 1 != 0 {
+        return Err(DecodeError::InvalidFormat);
+    }
+    nibbles
+        .chunks(2)
+        .map(|pair| match pair.iter().find(|&&nibble| nibble > 0x0f) {
+            Some(&nibble) => Err(DecodeError::InvalidNibble(nibble)),
+            None => Ok(pair[0] << 4 | pair[1]),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{PROTOCOLS, PROTOCOL_V1, PROTOCOL_V2};
+    use target::ATMEGA16;
+    use testing;
+
+    fn requests() -> Vec<Request> {
+        let page_data = (0..ATMEGA16.page_size)
+            .map(|byte| byte as u8 ^ 0xa5)
+            .collect();
+        vec![
+            Request::Ping(Ping {}),
+            Request::Write(Write::new(&ATMEGA16, 119, page_data).unwrap()),
+            Request::Read(Read::new(&ATMEGA16, 0).unwrap()),
+            Request::Verify(Verify::new(&ATMEGA16, 127).unwrap()),
+            Request::VerifyRange(VerifyRange::new(&ATMEGA16, 0, 119).unwrap()),
+            Request::Quit(Quit {}),
+        ]
+    }
+
+    #[test]
+    fn round_trip_in_every_protocol() {
+        for protocol in PROTOCOLS.iter() {
+            for request in requests() {
+                let message = request.to_sysex_with(protocol);
+                assert_eq!(protocol_of(&message), Ok(protocol));
+                assert_eq!(Request::from_sysex(&message, &ATMEGA16), Ok(request));
+            }
+        }
+    }
+
+    #[test]
+    fn to_sysex_speaks_version_1() {
+        for request in requests() {
+            assert_eq!(request.to_sysex(), request.to_sysex_with(&PROTOCOL_V1));
+        }
+        assert_eq!(
+            Ping {}.to_sysex(),
+            vec![0xf0, 0x00, 0x70, 0x01, 0x01, 0x00, 0x01, 0x00, 0xf7]
+        );
+    }
+
+    #[test]
+    fn both_encodings_carry_every_byte_value() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        for &encoding in &[Encoding::Nibbles, Encoding::Packed] {
+            let data = encoding.encode(&bytes);
+            assert!(data.iter().all(|&byte| byte < 0x80));
+            assert_eq!(encoding.decode(&data), Ok(bytes.clone()));
+        }
+    }
+
+    #[test]
+    fn payload_sizes_are_checked() {
+        for protocol in &[PROTOCOL_V1, PROTOCOL_V2] {
+            for payload in &[
+                &[COMMAND_PING, 0][..],
+                &[COMMAND_READ],
+                &[COMMAND_VERIFY, 1, 2],
+                &[COMMAND_WRITE, 0, 0xff],
+                &[COMMAND_VERIFY_RANGE, 0],
+            ] {
+                assert_eq!(
+                    Request::from_sysex(&testing::frame(protocol, payload), &ATMEGA16),
+                    Err(DecodeError::InvalidPayloadSize)
+                );
+            }
+            assert_eq!(
+                Request::from_sysex(&testing::frame(protocol, &[0x30]), &ATMEGA16),
+                Err(DecodeError::UnknownCommand(0x30))
+            );
+        }
+    }
+
+    #[test]
+    fn damaged_messages_are_rejected() {
+        let mut message = Ping {}.to_sysex();
+        message[5] ^= 0x01;
+        assert_eq!(from_sysex(&message), Err(DecodeError::InvalidChecksum));
+        message[5] = 0x11;
+        assert_eq!(from_sysex(&message), Err(DecodeError::InvalidNibble(0x11)));
+        message.pop();
+        assert_eq!(from_sysex(&message), Err(DecodeError::MissingFooter));
+        message[3] = 0x7f;
+        assert_eq!(
+            from_sysex(&message),
+            Err(DecodeError::VersionMismatch(0x7f))
+        );
+        message[2] = 0x71;
+        assert_eq!(from_sysex(&message), Err(DecodeError::HeaderMismatch));
+    }
+
+    #[test]
+    fn page_numbers_are_checked() {
+        let num_pages = ATMEGA16.num_pages();
+        assert_eq!(
+            Read::new(&ATMEGA16, num_pages),
+            Err(CommandError::InvalidPageNumber {
+                page_no: num_pages,
+                num_pages,
+            })
+        );
+        assert_eq!(
+            Write::new(&ATMEGA16, 0, vec![0; 64]),
+            Err(CommandError::InvalidPageSize {
+                expected: ATMEGA16.page_size,
+                actual: 64,
+            })
+        );
+        assert_eq!(
+            VerifyRange::new(&ATMEGA16, 4, 3),
+            Err(CommandError::InvalidPageRange {
+                first_page: 4,
+                last_page: 3,
+            })
+        );
     }
 }
@@ -0,0 +1,105 @@
+use std::time::{Duration, Instant};
+
+use command::{Command, Ping};
+use error::{BootloaderError, TransportError};
+use reply::Reply;
+use transport::Transport;
+
+// Pings through the transport and reports whether an electric-piano
+// bootloader answered within the timeout. Other traffic on the port, such as
+// sysex from other devices or corrupted frames, is ignored. A bootloader that
+// only speaks newer protocol versions answers with a header mismatch.
+pub fn probe<T: Transport>(transport: &mut T, timeout: Duration) -> Result<bool, TransportError> {
+    let deadline = Instant::now() + timeout;
+    transport.send(&Ping {}.to_sysex())?;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        match transport.receive(deadline - now) {
+            Ok(frame) => match Reply::from_sysex(&frame) {
+                Ok(Reply::Success) | Ok(Reply::Error(BootloaderError::HeaderMismatch)) => {
+                    return Ok(true)
+                }
+                _ => {}
+            },
+            Err(TransportError::Timeout) => return Ok(false),
+            Err(TransportError::Truncated(_)) => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+// Opens and probes every candidate port and returns those with a bootloader
+// listening. Ports that cannot be opened, e.g. because another program holds
+// them, or that fail while probed are skipped.
+pub fn discover<P, T, F>(candidates: Vec<P>, timeout: Duration, mut open: F) -> Vec<P>
+where
+    T: Transport,
+    F: FnMut(&P) -> Result<T, TransportError>,
+{
+    candidates
+        .into_iter()
+        .filter(|candidate| match open(candidate) {
+            Ok(mut transport) => probe(&mut transport, timeout).unwrap_or(false),
+            Err(_) => false,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::PROTOCOL_V3;
+    use target::ATMEGA16;
+    use transport::simulator::Simulator;
+
+    enum Port {
+        Board(Box<Simulator>),
+        Silent,
+        Unplugged,
+    }
+
+    impl Transport for Port {
+        fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+            match *self {
+                Port::Board(ref mut simulator) => simulator.send(message),
+                Port::Silent => Ok(()),
+                Port::Unplugged => Err(TransportError::Backend("unplugged".to_string())),
+            }
+        }
+
+        fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+            match *self {
+                Port::Board(ref mut simulator) => simulator.receive(timeout),
+                _ => Err(TransportError::Timeout),
+            }
+        }
+    }
+
+    fn timeout() -> Duration {
+        Duration::from_millis(10)
+    }
+
+    #[test]
+    fn probe_finds_bootloaders() {
+        let mut board = Simulator::new(ATMEGA16);
+        assert!(probe(&mut board, timeout()).unwrap());
+        let mut newer = Simulator::new(ATMEGA16).with_protocols(&[PROTOCOL_V3]);
+        assert!(probe(&mut newer, timeout()).unwrap());
+        assert!(!probe(&mut Port::Silent, timeout()).unwrap());
+        assert!(probe(&mut Port::Unplugged, timeout()).is_err());
+    }
+
+    #[test]
+    fn discover_skips_failing_ports() {
+        let found = discover(vec![0, 1, 2, 3, 4], timeout(), |&index| match index {
+            0 | 4 => Ok(Port::Board(Box::new(Simulator::new(ATMEGA16)))),
+            1 => Err(TransportError::Backend("busy".to_string())),
+            2 => Ok(Port::Unplugged),
+            _ => Ok(Port::Silent),
+        });
+        assert_eq!(found, vec![0, 4]);
+    }
+}
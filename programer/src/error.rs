@@ -0,0 +1,337 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use protocol::Checksum;
+use reply::Reply;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    HeaderMismatch,
+    VersionMismatch(u8),
+    MissingFooter,
+    InvalidFormat,
+    InvalidNibble(u8),
+    InvalidChecksum,
+    UnknownCommand(u8),
+    InvalidPayloadSize,
+    UnknownError(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::HeaderMismatch => {
+                write!(f, "message does not start with our sysex header")
+            }
+            DecodeError::VersionMismatch(version) => {
+                write!(f, "unsupported protocol version 0x{:02x}", version)
+            }
+            DecodeError::MissingFooter => write!(f, "message is not terminated by 0xf7"),
+            DecodeError::InvalidFormat => write!(f, "message body is malformed"),
+            DecodeError::InvalidNibble(byte) => {
+                write!(f, "invalid nibble 0x{:02x} in message body", byte)
+            }
+            DecodeError::InvalidChecksum => write!(f, "checksum mismatch"),
+            DecodeError::UnknownCommand(command) => {
+                write!(f, "unknown command 0x{:02x}", command)
+            }
+            DecodeError::InvalidPayloadSize => write!(f, "unexpected payload size"),
+            DecodeError::UnknownError(code) => write!(f, "unknown error code {}", code),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootloaderError {
+    HeaderMismatch = 1,
+    InvalidFormat = 2,
+    IncompleteMessage = 3,
+    InvalidNibble = 4,
+    InvalidChecksum = 5,
+    UnknownCommand = 6,
+    InvalidPayloadSize = 7,
+    InvalidPageNumber = 8,
+}
+
+impl BootloaderError {
+    pub fn from_code(code: u8) -> Option<BootloaderError> {
+        match code {
+            1 => Some(BootloaderError::HeaderMismatch),
+            2 => Some(BootloaderError::InvalidFormat),
+            3 => Some(BootloaderError::IncompleteMessage),
+            4 => Some(BootloaderError::InvalidNibble),
+            5 => Some(BootloaderError::InvalidChecksum),
+            6 => Some(BootloaderError::UnknownCommand),
+            7 => Some(BootloaderError::InvalidPayloadSize),
+            8 => Some(BootloaderError::InvalidPageNumber),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn likely_cause(self) -> &'static str {
+        match self {
+            BootloaderError::HeaderMismatch => {
+                "the message was meant for another device or uses a different protocol version"
+            }
+            BootloaderError::InvalidFormat => {
+                "the message ended before a command and checksum were received"
+            }
+            BootloaderError::IncompleteMessage => {
+                "a new message started before the previous one was terminated, \
+                 usually because bytes were dropped on the link"
+            }
+            BootloaderError::InvalidNibble => {
+                "a data byte above 0x0f arrived, usually a corrupted cable or \
+                 another device's running-status bytes merged into the stream"
+            }
+            BootloaderError::InvalidChecksum => "the message was corrupted in transit",
+            BootloaderError::UnknownCommand => {
+                "the host speaks a command this bootloader does not implement"
+            }
+            BootloaderError::InvalidPayloadSize => {
+                "the page data does not match the target's SPM page size"
+            }
+            BootloaderError::InvalidPageNumber => {
+                "the page lies beyond the end of flash, usually because the image is too big"
+            }
+        }
+    }
+}
+
+impl fmt::Display for BootloaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            BootloaderError::HeaderMismatch => "header mismatch",
+            BootloaderError::InvalidFormat => "invalid format",
+            BootloaderError::IncompleteMessage => "incomplete message",
+            BootloaderError::InvalidNibble => "invalid nibble",
+            BootloaderError::InvalidChecksum => "invalid checksum",
+            BootloaderError::UnknownCommand => "unknown command",
+            BootloaderError::InvalidPayloadSize => "invalid payload size",
+            BootloaderError::InvalidPageNumber => "invalid page number",
+        };
+        write!(
+            f,
+            "bootloader error {} ({}): {}",
+            self.code(),
+            name,
+            self.likely_cause()
+        )
+    }
+}
+
+impl error::Error for BootloaderError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    InvalidPageSize {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidPageNumber {
+        page_no: usize,
+        num_pages: usize,
+    },
+    ProtectedPage {
+        page_no: usize,
+        first_protected: usize,
+    },
+    InvalidPageRange {
+        first_page: usize,
+        last_page: usize,
+    },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::InvalidPageSize { expected, actual } => write!(
+                f,
+                "page data is {} bytes, but the target's pages are {} bytes",
+                actual, expected
+            ),
+            CommandError::InvalidPageNumber { page_no, num_pages } => write!(
+                f,
+                "page {} is out of range, the target has {} pages",
+                page_no, num_pages
+            ),
+            CommandError::ProtectedPage {
+                page_no,
+                first_protected,
+            } => write!(
+                f,
+                "page {} lies in the protected bootloader area starting at page {}",
+                page_no, first_protected
+            ),
+            CommandError::InvalidPageRange {
+                first_page,
+                last_page,
+            } => write!(f, "page range {} to {} is empty", first_page, last_page),
+        }
+    }
+}
+
+impl error::Error for CommandError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IhexErrorKind {
+    MissingStartCode,
+    InvalidHexDigit,
+    InvalidLength,
+    InvalidChecksum,
+    UnknownRecordType(u8),
+    OverlappingData(usize),
+    MissingEof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IhexError {
+    pub line: usize,
+    pub kind: IhexErrorKind,
+}
+
+impl fmt::Display for IhexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match self.kind {
+            IhexErrorKind::MissingStartCode => write!(f, "record does not start with ':'"),
+            IhexErrorKind::InvalidHexDigit => write!(f, "record contains invalid hex digits"),
+            IhexErrorKind::InvalidLength => write!(f, "record length does not match its type"),
+            IhexErrorKind::InvalidChecksum => write!(f, "record checksum mismatch"),
+            IhexErrorKind::UnknownRecordType(record_type) => {
+                write!(f, "unknown record type 0x{:02x}", record_type)
+            }
+            IhexErrorKind::OverlappingData(address) => {
+                write!(f, "address 0x{:04x} is defined twice", address)
+            }
+            IhexErrorKind::MissingEof => write!(f, "missing end-of-file record"),
+        }
+    }
+}
+
+impl error::Error for IhexError {}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Timeout,
+    Truncated(Vec<u8>),
+    Io(io::Error),
+    Backend(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransportError::Timeout => write!(f, "timed out waiting for a reply"),
+            TransportError::Truncated(ref frame) => {
+                write!(f, "reply was cut short after {} bytes", frame.len())
+            }
+            TransportError::Io(ref error) => write!(f, "i/o error: {}", error),
+            TransportError::Backend(ref message) => write!(f, "midi backend error: {}", message),
+        }
+    }
+}
+
+impl error::Error for TransportError {}
+
+impl From<io::Error> for TransportError {
+    fn from(error: io::Error) -> TransportError {
+        TransportError::Io(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Transport(TransportError),
+    Decode(DecodeError),
+    Bootloader(BootloaderError),
+    Command(CommandError),
+    UnexpectedReply(Reply),
+    VerifyMismatch {
+        page_no: usize,
+        expected: Checksum,
+        actual: Checksum,
+    },
+    NotErased {
+        page_no: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Transport(ref error) => error.fmt(f),
+            Error::Decode(ref error) => write!(f, "invalid reply: {}", error),
+            Error::Bootloader(ref error) => error.fmt(f),
+            Error::Command(ref error) => error.fmt(f),
+            Error::UnexpectedReply(ref reply) => write!(f, "unexpected reply {:?}", reply),
+            Error::VerifyMismatch {
+                page_no,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "page {} has checksum {}, expected {}",
+                page_no, actual, expected
+            ),
+            Error::NotErased { page_no } => {
+                write!(f, "page {} should be blank but is not erased", page_no)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<TransportError> for Error {
+    fn from(error: TransportError) -> Error {
+        Error::Transport(error)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(error: DecodeError) -> Error {
+        Error::Decode(error)
+    }
+}
+
+impl From<BootloaderError> for Error {
+    fn from(error: BootloaderError) -> Error {
+        Error::Bootloader(error)
+    }
+}
+
+impl From<CommandError> for Error {
+    fn from(error: CommandError) -> Error {
+        Error::Command(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_match_firmware() {
+        for code in 1..=8 {
+            let error = BootloaderError::from_code(code).unwrap();
+            assert_eq!(error.code(), code);
+        }
+        assert_eq!(BootloaderError::from_code(0), None);
+        assert_eq!(BootloaderError::from_code(9), None);
+        assert_eq!(BootloaderError::InvalidChecksum.code(), 5);
+    }
+
+    #[test]
+    fn display_names_the_code() {
+        let message = BootloaderError::InvalidPageNumber.to_string();
+        assert!(message.starts_with("bootloader error 8 (invalid page number): "));
+    }
+}
@@ -0,0 +1,161 @@
+use command::{FOOTER, HEADER};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Frame(Vec<u8>),
+    Truncated(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    MatchingHeader,
+    ReadingBody,
+}
+
+// Splits a raw MIDI byte stream into complete sysex frames addressed to us.
+// Realtime bytes are dropped wherever they appear, sysex for other
+// manufacturers and channel messages are skipped, and a frame cut short by
+// another status byte is reported as truncated.
+pub struct Framer {
+    state: State,
+    frame: Vec<u8>,
+}
+
+impl Framer {
+    pub fn new() -> Framer {
+        Framer {
+            state: State::Idle,
+            frame: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Event> {
+        bytes
+            .iter()
+            .filter_map(|&byte| self.push_byte(byte))
+            .collect()
+    }
+
+    pub fn push_byte(&mut self, byte: u8) -> Option<Event> {
+        match byte {
+            0xf8..=0xff => None,
+            0xf0 => {
+                let event = self.abort();
+                self.state = State::MatchingHeader;
+                self.frame.push(byte);
+                event
+            }
+            _ if byte == FOOTER[0] => {
+                // Frames that never got past our header are not for us.
+                if self.state != State::ReadingBody {
+                    self.state = State::Idle;
+                    self.frame.clear();
+                    return None;
+                }
+                self.state = State::Idle;
+                self.frame.push(byte);
+                Some(Event::Frame(self.frame.split_off(0)))
+            }
+            0x80..=0xff => {
+                let event = self.abort();
+                self.state = State::Idle;
+                event
+            }
+            _ => {
+                match self.state {
+                    State::Idle => {}
+                    State::MatchingHeader => {
+                        // The version byte is left to the decoder.
+                        let index = self.frame.len();
+                        if index < HEADER.len() - 1 && byte != HEADER[index] {
+                            self.state = State::Idle;
+                            self.frame.clear();
+                            return None;
+                        }
+                        self.frame.push(byte);
+                        if self.frame.len() == HEADER.len() {
+                            self.state = State::ReadingBody;
+                        }
+                    }
+                    State::ReadingBody => self.frame.push(byte),
+                }
+                None
+            }
+        }
+    }
+
+    fn abort(&mut self) -> Option<Event> {
+        if self.state == State::Idle {
+            return None;
+        }
+        self.state = State::Idle;
+        Some(Event::Truncated(self.frame.split_off(0)))
+    }
+}
+
+impl Default for Framer {
+    fn default() -> Framer {
+        Framer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Command, Ping};
+
+    #[test]
+    fn realtime_bytes_are_dropped() {
+        let ping = Ping {}.to_sysex();
+        let mut bytes = vec![0xf8];
+        for &byte in &ping {
+            bytes.push(byte);
+            bytes.push(0xfe);
+        }
+        assert_eq!(Framer::new().push(&bytes), vec![Event::Frame(ping)]);
+    }
+
+    #[test]
+    fn status_byte_truncates_frame() {
+        let ping = Ping {}.to_sysex();
+        let mut bytes = ping[..6].to_vec();
+        bytes.extend(&[0x90, 0x3c, 0x40]);
+        bytes.extend(&ping);
+        assert_eq!(
+            Framer::new().push(&bytes),
+            vec![Event::Truncated(ping[..6].to_vec()), Event::Frame(ping)]
+        );
+    }
+
+    #[test]
+    fn start_of_frame_truncates_frame() {
+        let ping = Ping {}.to_sysex();
+        let mut bytes = ping[..5].to_vec();
+        bytes.extend(&ping);
+        assert_eq!(
+            Framer::new().push(&bytes),
+            vec![Event::Truncated(ping[..5].to_vec()), Event::Frame(ping)]
+        );
+    }
+
+    #[test]
+    fn foreign_sysex_is_skipped() {
+        let mut bytes = vec![0xf0, 0x43, 0x10, 0x4c, 0x00, 0xf7];
+        bytes.extend(&[0xf0, 0xf7]);
+        bytes.extend(&[0xf0, 0x00, 0xf7]);
+        bytes.extend(&[0x80, 0x3c, 0x00, 0xf7]);
+        assert_eq!(Framer::new().push(&bytes), vec![]);
+    }
+
+    #[test]
+    fn input_split_across_pushes() {
+        let ping = Ping {}.to_sysex();
+        let mut framer = Framer::new();
+        let mut events = Vec::new();
+        for chunk in ping.chunks(3).chain(ping.chunks(1)) {
+            events.extend(framer.push(chunk));
+        }
+        assert_eq!(events, vec![Event::Frame(ping.clone()), Event::Frame(ping)]);
+    }
+}
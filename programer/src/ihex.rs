@@ -0,0 +1,246 @@
+use error::{IhexError, IhexErrorKind};
+use image::Image;
+
+const RECORD_DATA: u8 = 0x00;
+const RECORD_EOF: u8 = 0x01;
+const RECORD_EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const RECORD_START_SEGMENT_ADDRESS: u8 = 0x03;
+const RECORD_EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const RECORD_START_LINEAR_ADDRESS: u8 = 0x05;
+
+const BYTES_PER_RECORD: usize = 16;
+
+pub fn parse(text: &str) -> Result<Image, IhexError> {
+    let mut image = Image::new();
+    let mut base = 0;
+    let mut line_no = 0;
+
+    for line in text.lines() {
+        line_no += 1;
+        let error = |kind| IhexError {
+            line: line_no,
+            kind,
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(':') {
+            return Err(error(IhexErrorKind::MissingStartCode));
+        }
+
+        let record = from_hex(&line[1..]).ok_or_else(|| error(IhexErrorKind::InvalidHexDigit))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(error(IhexErrorKind::InvalidLength));
+        }
+        let checksum = record.iter().fold(0u8, |acc, val| acc.wrapping_add(*val));
+        if checksum != 0 {
+            return Err(error(IhexErrorKind::InvalidChecksum));
+        }
+
+        let offset = (record[1] as usize) << 8 | record[2] as usize;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            RECORD_DATA => {
+                for (i, &byte) in data.iter().enumerate() {
+                    let address = base + ((offset + i) & 0xffff);
+                    if image.set(address, byte).is_some() {
+                        return Err(error(IhexErrorKind::OverlappingData(address)));
+                    }
+                }
+            }
+            RECORD_EOF => return Ok(image),
+            RECORD_EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                base = ((data[0] as usize) << 8 | data[1] as usize) << 4;
+            }
+            RECORD_EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                base = ((data[0] as usize) << 8 | data[1] as usize) << 16;
+            }
+            RECORD_START_SEGMENT_ADDRESS | RECORD_START_LINEAR_ADDRESS if data.len() == 4 => {}
+            RECORD_EXTENDED_SEGMENT_ADDRESS
+            | RECORD_EXTENDED_LINEAR_ADDRESS
+            | RECORD_START_SEGMENT_ADDRESS
+            | RECORD_START_LINEAR_ADDRESS => return Err(error(IhexErrorKind::InvalidLength)),
+            record_type => return Err(error(IhexErrorKind::UnknownRecordType(record_type))),
+        }
+    }
+
+    Err(IhexError {
+        line: line_no + 1,
+        kind: IhexErrorKind::MissingEof,
+    })
+}
+
+pub fn to_string(image: &Image) -> String {
+    let mut text = String::new();
+    let mut base = 0;
+    let mut record: Vec<u8> = Vec::new();
+    let mut record_start = 0;
+
+    let bytes: Vec<(usize, u8)> = image.iter().collect();
+    for (i, &(address, byte)) in bytes.iter().enumerate() {
+        if record.is_empty() {
+            record_start = address;
+            if address >> 16 != base {
+                base = address >> 16;
+                let upper = [(base >> 8) as u8, base as u8];
+                push_record(&mut text, RECORD_EXTENDED_LINEAR_ADDRESS, 0, &upper);
+            }
+        }
+        record.push(byte);
+
+        let next = bytes.get(i + 1).map(|&(address, _)| address);
+        let contiguous = next == Some(address + 1);
+        let full = record.len() == BYTES_PER_RECORD;
+        let boundary = (address + 1) & 0xffff == 0;
+        if !contiguous || full || boundary {
+            push_record(&mut text, RECORD_DATA, record_start & 0xffff, &record);
+            record.clear();
+        }
+    }
+
+    push_record(&mut text, RECORD_EOF, 0, &[]);
+    text
+}
+
+fn push_record(text: &mut String, record_type: u8, offset: usize, data: &[u8]) {
+    let mut record = vec![
+        data.len() as u8,
+        (offset >> 8) as u8,
+        offset as u8,
+        record_type,
+    ];
+    record.extend(data);
+    let checksum = record.iter().fold(0u8, |acc, val| acc.wrapping_add(*val));
+    record.push(checksum.wrapping_neg());
+
+    text.push(':');
+    for byte in record {
+        text.push_str(&format!("{:02X}", byte));
+    }
+    text.push('\n');
+}
+
+fn from_hex(digits: &str) -> Option<Vec<u8>> {
+    if digits.len() & 1 != 0 || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> IhexError {
+        parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn data_records() {
+        let image = parse(":0400000001020304F2\r\n:00000001FF\r\n:garbage after eof").unwrap();
+        assert_eq!(image.to_binary(), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn extended_segment_address() {
+        let image = parse(":020000021000EC\n:01001000AA45\n:00000001FF\n").unwrap();
+        assert_eq!(image.iter().collect::<Vec<_>>(), vec![(0x10010, 0xaa)]);
+    }
+
+    #[test]
+    fn extended_linear_address() {
+        let text = ":0400000300000000F9\n:020000040001F9\n:01001000AA45\n\
+                    :0400000500000000F7\n:00000001FF\n";
+        let image = parse(text).unwrap();
+        assert_eq!(image.iter().collect::<Vec<_>>(), vec![(0x10010, 0xaa)]);
+    }
+
+    #[test]
+    fn round_trip_across_64k() {
+        let mut image = Image::new();
+        for address in (0xffe0..0x10020).chain(0x10030..0x10033) {
+            image.set(address, address as u8 ^ 0x5a);
+        }
+        image.set(0x20000, 0x42);
+        let text = to_string(&image);
+        assert_eq!(parse(&text).unwrap(), image);
+        assert_eq!(
+            text.lines()
+                .filter(|line| line.starts_with(":02000004"))
+                .count(),
+            2
+        );
+        assert!(text
+            .lines()
+            .all(|line| line.len() <= 11 + 2 * BYTES_PER_RECORD));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let eof = ":00000001FF\n";
+        assert_eq!(
+            error(&format!(":0400000001020304F3\n{}", eof)),
+            IhexError {
+                line: 1,
+                kind: IhexErrorKind::InvalidChecksum,
+            }
+        );
+        assert_eq!(
+            error(&format!(
+                ":0400000001020304F2\n:04000000010203G4F2\n{}",
+                eof
+            )),
+            IhexError {
+                line: 2,
+                kind: IhexErrorKind::InvalidHexDigit,
+            }
+        );
+        assert_eq!(
+            error(&format!("\n:0500000001020304F1\n{}", eof)),
+            IhexError {
+                line: 2,
+                kind: IhexErrorKind::InvalidLength,
+            }
+        );
+        assert_eq!(
+            error(&format!(":03000002100000EB\n{}", eof)),
+            IhexError {
+                line: 1,
+                kind: IhexErrorKind::InvalidLength,
+            }
+        );
+        assert_eq!(
+            error("0400000001020304F2\n"),
+            IhexError {
+                line: 1,
+                kind: IhexErrorKind::MissingStartCode,
+            }
+        );
+        assert_eq!(
+            error(&format!(":00000006FA\n{}", eof)),
+            IhexError {
+                line: 1,
+                kind: IhexErrorKind::UnknownRecordType(6),
+            }
+        );
+        assert_eq!(
+            error(":0400000001020304F2\n:0100020009F4\n"),
+            IhexError {
+                line: 2,
+                kind: IhexErrorKind::OverlappingData(2),
+            }
+        );
+        assert_eq!(
+            error(":0400000001020304F2\n"),
+            IhexError {
+                line: 2,
+                kind: IhexErrorKind::MissingEof,
+            }
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use command::{self, Write};
+use error::CommandError;
+use protection::Protection;
+use target::Target;
+
+pub const ERASED: u8 = 0xff;
+
+// A sparse memory image, as loaded from a firmware file. Addresses not
+// present in the image are left untouched by the tooling.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    bytes: BTreeMap<usize, u8>,
+}
+
+impl Image {
+    pub fn new() -> Image {
+        Image {
+            bytes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, address: usize) -> Option<u8> {
+        self.bytes.get(&address).cloned()
+    }
+
+    pub fn set(&mut self, address: usize, byte: u8) -> Option<u8> {
+        self.bytes.insert(address, byte)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn start(&self) -> Option<usize> {
+        self.bytes.keys().next().cloned()
+    }
+
+    pub fn end(&self) -> Option<usize> {
+        self.bytes.keys().next_back().map(|address| address + 1)
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (usize, u8)> + 'a {
+        self.bytes.iter().map(|(&address, &byte)| (address, byte))
+    }
+
+    // Raw contents from address 0 up to the last byte set, gaps erased.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = vec![ERASED; self.end().unwrap_or(0)];
+        for (address, byte) in self.iter() {
+            binary[address] = byte;
+        }
+        binary
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Image {
+        let mut image = Image::new();
+        for (address, &byte) in binary.iter().enumerate() {
+            image.set(address, byte);
+        }
+        image
+    }
+
+    pub fn page_numbers(&self, target: &Target) -> Vec<usize> {
+        let mut page_numbers: Vec<usize> = self
+            .bytes
+            .keys()
+            .map(|&address| target.page_of(address))
+            .collect();
+        page_numbers.dedup();
+        page_numbers
+    }
+
+    pub fn page(&self, target: &Target, page_no: usize) -> Vec<u8> {
+        let start = page_no * target.page_size;
+        (start..start + target.page_size)
+            .map(|address| self.get(address).unwrap_or(ERASED))
+            .collect()
+    }
+
+    // CRC-16/CCITT over whole pages as they end up in flash, with the gaps
+    // erased.
+    pub fn crc16(&self, target: &Target, pages: Range<usize>) -> u16 {
+        let bytes: Vec<u8> = pages
+            .flat_map(|page_no| self.page(target, page_no))
+            .collect();
+        command::crc16(&bytes)
+    }
+
+    // Page 0 holds the reset vector, so it is written last: an interrupted
+    // flash leaves the bootloader in charge instead of a half-written app.
+    pub fn to_writes(
+        &self,
+        target: &Target,
+        protection: &Protection,
+        skip_blank: bool,
+    ) -> Result<Vec<Write>, CommandError> {
+        let mut page_numbers = self.page_numbers(target);
+        for &page_no in &page_numbers {
+            if page_no >= target.num_pages() {
+                return Err(CommandError::InvalidPageNumber {
+                    page_no,
+                    num_pages: target.num_pages(),
+                });
+            }
+            protection.check_page(page_no)?;
+        }
+        if !page_numbers.is_empty() && page_numbers[0] == 0 {
+            page_numbers.rotate_left(1);
+        }
+
+        let mut writes = Vec::new();
+        for page_no in page_numbers {
+            let page_data = self.page(target, page_no);
+            if skip_blank && page_data.iter().all(|&byte| byte == ERASED) {
+                continue;
+            }
+            writes.push(Write::new(target, page_no, page_data)?);
+        }
+        Ok(writes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use target::ATMEGA16;
+
+    #[test]
+    fn image_past_end_of_flash_is_invalid_page_number() {
+        let mut image = Image::new();
+        image.set(ATMEGA16.flash_size, 0x42);
+        let protection = Protection::dangerously_allow_bootloader_overwrite(&ATMEGA16);
+        assert_eq!(
+            image.to_writes(&ATMEGA16, &protection, false),
+            Err(CommandError::InvalidPageNumber {
+                page_no: ATMEGA16.num_pages(),
+                num_pages: ATMEGA16.num_pages(),
+            })
+        );
+        assert_eq!(
+            image.to_writes(&ATMEGA16, &Protection::new(&ATMEGA16), false),
+            Err(CommandError::InvalidPageNumber {
+                page_no: ATMEGA16.num_pages(),
+                num_pages: ATMEGA16.num_pages(),
+            })
+        );
+    }
+
+    #[test]
+    fn bootloader_page_is_protected() {
+        let mut image = Image::new();
+        image.set(ATMEGA16.boot_start, 0x42);
+        assert_eq!(
+            image.to_writes(&ATMEGA16, &Protection::new(&ATMEGA16), false),
+            Err(CommandError::ProtectedPage {
+                page_no: ATMEGA16.boot_page(),
+                first_protected: ATMEGA16.boot_page(),
+            })
+        );
+    }
+}
@@ -1,3 +1,41 @@
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+extern crate alsa;
+#[cfg(target_os = "linux")]
+extern crate libc;
+#[cfg(feature = "portmidi")]
+extern crate portmidi as pm;
+
 pub mod command;
 
+pub mod discovery;
+
+pub mod error;
+
+pub mod framer;
+
+pub mod ihex;
+
+pub mod image;
+
+pub mod policy;
+
+pub mod programmer;
+
+pub mod protection;
+
+pub mod protocol;
+
 pub mod reply;
+
+pub mod session;
+
+pub mod syx;
+
+pub mod target;
+
+pub mod trace;
+
+pub mod transport;
+
+#[cfg(test)]
+mod testing;
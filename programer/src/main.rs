@@ -1,20 +1,757 @@
+#[cfg(feature = "portmidi")]
 extern crate portmidi as pm;
-
 extern crate sysexprog;
 
-use sysexprog::command::*;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+use sysexprog::error::{CommandError, Error, TransportError};
+use sysexprog::ihex;
+use sysexprog::image::Image;
+use sysexprog::policy::RetryPolicy;
+use sysexprog::programmer::{Programmer, Progress, Report};
+use sysexprog::protection::Protection;
+use sysexprog::protocol::Protocol;
+use sysexprog::session::Session;
+use sysexprog::syx;
+use sysexprog::target::{Target, ATMEGA16};
+use sysexprog::trace;
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+use sysexprog::transport::alsa::AlsaTransport;
+#[cfg(feature = "portmidi")]
+use sysexprog::transport::portmidi::{self, PortMidiTransport};
+#[cfg(target_os = "linux")]
+use sysexprog::transport::serial::SerialTransport;
+use sysexprog::transport::simulator::Simulator;
+use sysexprog::transport::Transport;
+
+const USAGE: &str = "\
+usage: sysexprog [options] <command> [arguments]
+
+commands:
+  list-ports              list MIDI ports, marking those with a bootloader
+  ping                    check that the bootloader answers
+  flash <image>           write an Intel HEX or binary image and verify it
+  verify <image>          compare the flash with an image by checksum
+  dump <out>              read the application section into a .hex or .bin file
+  read-page <n>           print one page of flash
+  quit                    leave the bootloader and start the application
+  export <image> <out>    write a flashing job as .syx with a delay manifest
+  trace <capture>         decode a .syx file or raw MIDI capture
+
+options:
+  -p, --port <port>       MIDI port by name or list-ports index
+  -b, --backend <name>    MIDI backend, portmidi or alsa
+  -s, --serial <device>   use a serial MIDI interface instead of a MIDI port
+  -t, --target <mcu>      target MCU, atmega16 by default
+  --protocol <version>    protocol version to speak instead of the newest
+                          one the board accepts, export and dry runs use 1
+                          by default
+  --hfuse <value>         protect the boot section configured by this high fuse
+  --incremental           only write pages that differ from the device
+  --write-blank           write pages that are all 0xff, which flash otherwise
+                          only checks for being erased
+  --all                   dump the bootloader section as well
+  --replay                send the commands of a trace and check the replies
+  -n, --dry-run           print the messages instead of sending them
+  -h, --help              show this help
+
+exit codes:
+  0 success, 1 usage, 2 file error, 3 transport error, 4 verify mismatch,
+  5 command refused by the host, 6 invalid reply,
+  7 replayed replies differ from the capture, 11-18 bootloader error 1-8
+";
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_FILE: i32 = 2;
+const EXIT_TRANSPORT: i32 = 3;
+const EXIT_VERIFY: i32 = 4;
+const EXIT_REFUSED: i32 = 5;
+const EXIT_REPLY: i32 = 6;
+const EXIT_REPLAY: i32 = 7;
+// Bootloader errors exit with their error code added to this.
+const EXIT_BOOTLOADER: i32 = 10;
+
+struct Failure {
+    code: i32,
+    message: String,
+}
+
+impl Failure {
+    fn usage<S: Into<String>>(message: S) -> Failure {
+        Failure {
+            code: EXIT_USAGE,
+            message: message.into(),
+        }
+    }
+
+    fn file<E: Display>(path: &str, error: E) -> Failure {
+        Failure {
+            code: EXIT_FILE,
+            message: format!("{}: {}", path, error),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Failure {
+        let code = match error {
+            Error::Transport(_) => EXIT_TRANSPORT,
+            Error::Decode(_) | Error::UnexpectedReply(_) => EXIT_REPLY,
+            Error::Bootloader(error) => EXIT_BOOTLOADER + error.code() as i32,
+            Error::Command(_) => EXIT_REFUSED,
+            Error::VerifyMismatch { .. } | Error::NotErased { .. } => EXIT_VERIFY,
+        };
+        Failure {
+            code,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<TransportError> for Failure {
+    fn from(error: TransportError) -> Failure {
+        Failure::from(Error::from(error))
+    }
+}
+
+impl From<CommandError> for Failure {
+    fn from(error: CommandError) -> Failure {
+        Failure::from(Error::from(error))
+    }
+}
+
+struct Options {
+    command: String,
+    arguments: Vec<String>,
+    port: Option<String>,
+    backend: Option<String>,
+    serial: Option<String>,
+    target: Target,
+    protocol: Option<Protocol>,
+    high_fuse: Option<u8>,
+    incremental: bool,
+    write_blank: bool,
+    all: bool,
+    replay: bool,
+    dry_run: bool,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, Failure> {
+        let mut options = Options {
+            command: String::new(),
+            arguments: Vec::new(),
+            port: None,
+            backend: None,
+            serial: None,
+            target: ATMEGA16,
+            protocol: None,
+            high_fuse: None,
+            incremental: false,
+            write_blank: false,
+            all: false,
+            replay: false,
+            dry_run: false,
+        };
+        let mut positional = Vec::new();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| Failure::usage(format!("{} needs a value", arg)))
+            };
+            match arg.as_str() {
+                "-p" | "--port" => options.port = Some(value()?),
+                "-b" | "--backend" => options.backend = Some(value()?),
+                "-s" | "--serial" => options.serial = Some(value()?),
+                "-t" | "--target" => {
+                    let name = value()?;
+                    options.target = *Target::by_name(&name)
+                        .ok_or_else(|| Failure::usage(format!("unknown target '{}'", name)))?;
+                }
+                "--protocol" => {
+                    let version = value()?;
+                    let protocol = parse_number(&version)
+                        .filter(|&version| version <= 0xff)
+                        .and_then(|version| Protocol::by_version(version as u8))
+                        .ok_or_else(|| {
+                            Failure::usage(format!("unknown protocol version '{}'", version))
+                        })?;
+                    options.protocol = Some(*protocol);
+                }
+                "--hfuse" => {
+                    let fuse = value()?;
+                    match parse_number(&fuse) {
+                        Some(fuse) if fuse <= 0xff => options.high_fuse = Some(fuse as u8),
+                        _ => return Err(Failure::usage(format!("invalid fuse value '{}'", fuse))),
+                    }
+                }
+                "--incremental" => options.incremental = true,
+                "--write-blank" => options.write_blank = true,
+                "--all" => options.all = true,
+                "--replay" => options.replay = true,
+                "-n" | "--dry-run" => options.dry_run = true,
+                "-h" | "--help" => positional.insert(0, "help".to_string()),
+                _ if arg.starts_with('-') => {
+                    return Err(Failure::usage(format!("unknown option '{}'", arg)))
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        if positional.is_empty() {
+            return Err(Failure::usage("no command given"));
+        }
+        options.command = positional.remove(0);
+        options.arguments = positional;
+
+        let expected = match options.command.as_str() {
+            "help" => options.arguments.len(),
+            "list-ports" | "ping" | "quit" => 0,
+            "flash" | "verify" | "dump" | "read-page" | "trace" => 1,
+            "export" => 2,
+            command => return Err(Failure::usage(format!("unknown command '{}'", command))),
+        };
+        if options.arguments.len() != expected {
+            return Err(Failure::usage(format!(
+                "{} takes {} argument(s)",
+                options.command, expected
+            )));
+        }
+        Ok(options)
+    }
+
+    fn protection(&self) -> Protection {
+        match self.high_fuse {
+            Some(fuse) => Protection::with_high_fuse(&self.target, fuse),
+            None => Protection::new(&self.target),
+        }
+    }
+
+    fn backend(&self) -> Result<&str, Failure> {
+        if let Some(ref backend) = self.backend {
+            return Ok(backend);
+        }
+        if cfg!(feature = "portmidi") {
+            Ok("portmidi")
+        } else if cfg!(all(target_os = "linux", feature = "alsa")) {
+            Ok("alsa")
+        } else {
+            Err(Failure::usage(
+                "this build has no MIDI backend, use --serial or --dry-run",
+            ))
+        }
+    }
+}
+
+// Prints every message instead of sending it and answers from a simulated
+// board with erased flash, so whole jobs can be rehearsed without hardware.
+struct DryRun {
+    simulator: Simulator,
+}
+
+impl Transport for DryRun {
+    fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        println!("{}", hex(message));
+        self.simulator.send(message)
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        self.simulator.receive(timeout)
+    }
+}
 
 fn main() {
-    //  let context = pm::PortMidi::new().unwrap();
-
-    /*  let device_id = 3;
-  hallo();
-  context
-    .device(device_id)
-    .and_then(|dev| context.output_port(dev, 1024))
-    .and_then(|port| port.write_sysex(0, &[240, 0, 112, 1, 1, 0, 1, 0, 247])).unwrap();
-*/
-
-    let ping = Ping {};
-    print!("{:?}", ping.to_sysex());
+    let code = match Options::parse(env::args().skip(1)).and_then(|options| run(&options)) {
+        Ok(()) => 0,
+        Err(failure) => {
+            eprintln!("sysexprog: {}", failure.message);
+            if failure.code == EXIT_USAGE {
+                eprint!("\n{}", USAGE);
+            }
+            failure.code
+        }
+    };
+    process::exit(code);
+}
+
+fn run(options: &Options) -> Result<(), Failure> {
+    match options.command.as_str() {
+        "help" => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        "list-ports" => list_ports(options),
+        "export" => export(options),
+        "trace" if !options.replay => trace(options),
+        _ => connect(options),
+    }
+}
+
+fn connect(options: &Options) -> Result<(), Failure> {
+    if options.dry_run {
+        // Shows what the bootloader in firmware/ would receive.
+        let protocol = options.protocol.unwrap_or_default();
+        let simulator = Simulator::new(options.target).with_protocols(&[protocol]);
+        return execute(options, DryRun { simulator });
+    }
+    if let Some(ref path) = options.serial {
+        return open_serial(options, path);
+    }
+    match options.backend()? {
+        #[cfg(feature = "portmidi")]
+        "portmidi" => {
+            let context = pm::PortMidi::new().map_err(portmidi::backend_error)?;
+            let (input, output) = select_portmidi(&context, options)?;
+            let transport = PortMidiTransport::open(&context, input, output)?;
+            execute(options, transport)
+        }
+        #[cfg(all(target_os = "linux", feature = "alsa"))]
+        "alsa" => {
+            let id = select_alsa(options)?;
+            execute(options, AlsaTransport::open(&id)?)
+        }
+        backend => Err(Failure::usage(format!(
+            "backend '{}' is not available in this build",
+            backend
+        ))),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_serial(options: &Options, path: &str) -> Result<(), Failure> {
+    execute(options, SerialTransport::open(path)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_serial(_options: &Options, _path: &str) -> Result<(), Failure> {
+    Err(Failure::usage(
+        "serial interfaces are only supported on linux",
+    ))
+}
+
+fn execute<T: Transport>(options: &Options, transport: T) -> Result<(), Failure> {
+    // A replay should show what the board answers, not what retries hide.
+    let policy = if options.replay {
+        RetryPolicy::no_retries()
+    } else {
+        RetryPolicy::default()
+    };
+    let mut session = Session::new(transport, options.target)
+        .with_protection(options.protection())
+        .with_policy(policy);
+    match options.protocol {
+        Some(protocol) => session = session.with_protocol(protocol),
+        None if options.dry_run => {}
+        None => {
+            session.negotiate()?;
+        }
+    }
+    let mut programmer = Programmer::new(session)
+        .with_skip_blank(!options.write_blank)
+        .with_incremental(options.incremental);
+    // A dry run prints every message, progress would only garble that.
+    if !options.dry_run {
+        programmer = programmer.on_progress(print_progress);
+    }
+
+    match options.command.as_str() {
+        "ping" => {
+            programmer.session().ping()?;
+            println!(
+                "bootloader is listening, protocol version {}",
+                programmer.session().protocol().version
+            );
+        }
+        "flash" => {
+            let image = load_image(&options.arguments[0])?;
+            let report = programmer.flash(&image)?;
+            print_report(&report);
+            check_report(report)?;
+            print_crc(options, "image", &image);
+        }
+        "verify" => {
+            let image = load_image(&options.arguments[0])?;
+            let report = programmer.verify(&image)?;
+            for (page_no, error) in &report.failed {
+                println!("page {}: {}", page_no, error);
+            }
+            check_report(report)?;
+            println!("flash matches {}", options.arguments[0]);
+            print_crc(options, "image", &image);
+        }
+        "dump" => {
+            let image = programmer.read_flash(options.all)?;
+            save_image(&options.arguments[0], &image)?;
+            print_crc(options, "flash", &image);
+        }
+        "read-page" => {
+            let page_no = parse_number(&options.arguments[0]).ok_or_else(|| {
+                Failure::usage(format!("invalid page number '{}'", options.arguments[0]))
+            })?;
+            let page_data = programmer.session().read(page_no)?;
+            let base = page_no * options.target.page_size;
+            for (row, chunk) in page_data.chunks(16).enumerate() {
+                println!("{:04x}: {}", base + row * 16, hex(chunk));
+            }
+        }
+        "quit" => programmer.session().quit()?,
+        "trace" => {
+            let entries = trace::decode(&load_capture(&options.arguments[0])?, &options.target);
+            let steps = trace::replay(programmer.session(), &entries);
+            for step in &steps {
+                println!("{}", step);
+            }
+            let mismatches = steps.iter().filter(|step| !step.is_match()).count();
+            if mismatches > 0 {
+                return Err(Failure {
+                    code: EXIT_REPLAY,
+                    message: format!(
+                        "{} of {} replies differ from the capture",
+                        mismatches,
+                        steps.len()
+                    ),
+                });
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "portmidi")]
+fn select_portmidi(
+    context: &pm::PortMidi,
+    options: &Options,
+) -> Result<(pm::DeviceInfo, pm::DeviceInfo), Failure> {
+    let pairs = portmidi::port_pairs(context)?;
+    let found = match options.port {
+        Some(ref port) => match port.parse::<usize>() {
+            Ok(index) => pairs.get(index).cloned(),
+            Err(_) => pairs
+                .iter()
+                .find(|(input, output)| input.name().contains(port) && output.name().contains(port))
+                .cloned(),
+        },
+        None => {
+            let mut found = portmidi::discover(context, discovery_timeout())?;
+            if found.len() > 1 {
+                return Err(Failure::usage(
+                    "several ports have a bootloader, pick one with --port",
+                ));
+            }
+            found.pop()
+        }
+    };
+    found.ok_or_else(|| no_port(options))
+}
+
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+fn select_alsa(options: &Options) -> Result<String, Failure> {
+    let ports = AlsaTransport::ports()?;
+    let found = match options.port {
+        Some(ref port) => match port.parse::<usize>() {
+            Ok(index) => ports.get(index),
+            Err(_) => ports
+                .iter()
+                .find(|candidate| candidate.id == *port || candidate.name.contains(port)),
+        }
+        .map(|port| port.id.clone()),
+        None => {
+            let mut found = AlsaTransport::discover(discovery_timeout())?;
+            if found.len() > 1 {
+                return Err(Failure::usage(
+                    "several ports have a bootloader, pick one with --port",
+                ));
+            }
+            found.pop().map(|port| port.id)
+        }
+    };
+    found.ok_or_else(|| no_port(options))
+}
+
+#[cfg(any(feature = "portmidi", all(target_os = "linux", feature = "alsa")))]
+fn no_port(options: &Options) -> Failure {
+    let message = match options.port {
+        Some(ref port) => format!("no MIDI port matches '{}'", port),
+        None => "no bootloader found, is the board in bootloader mode?".to_string(),
+    };
+    Failure {
+        code: EXIT_TRANSPORT,
+        message,
+    }
+}
+
+#[cfg(any(feature = "portmidi", all(target_os = "linux", feature = "alsa")))]
+fn discovery_timeout() -> Duration {
+    RetryPolicy::default().ping.timeout
+}
+
+fn list_ports(options: &Options) -> Result<(), Failure> {
+    match options.backend()? {
+        #[cfg(feature = "portmidi")]
+        "portmidi" => {
+            let context = pm::PortMidi::new().map_err(portmidi::backend_error)?;
+            let found = portmidi::discover(&context, discovery_timeout())?;
+            for (index, (input, output)) in portmidi::port_pairs(&context)?.iter().enumerate() {
+                let listening = found
+                    .iter()
+                    .any(|(i, o)| i.id() == input.id() && o.id() == output.id());
+                println!(
+                    "{}: {} -> {}{}",
+                    index,
+                    input.name(),
+                    output.name(),
+                    if listening { " (bootloader)" } else { "" }
+                );
+            }
+            Ok(())
+        }
+        #[cfg(all(target_os = "linux", feature = "alsa"))]
+        "alsa" => {
+            let found = AlsaTransport::discover(discovery_timeout())?;
+            for (index, port) in AlsaTransport::ports()?.iter().enumerate() {
+                println!(
+                    "{}: {} {}{}",
+                    index,
+                    port.id,
+                    port.name,
+                    if found.contains(port) {
+                        " (bootloader)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            Ok(())
+        }
+        backend => Err(Failure::usage(format!(
+            "backend '{}' is not available in this build",
+            backend
+        ))),
+    }
+}
+
+fn export(options: &Options) -> Result<(), Failure> {
+    let image = load_image(&options.arguments[0])?;
+    let messages = syx::export(
+        &image,
+        &options.target,
+        &options.protection(),
+        &options.protocol.unwrap_or_default(),
+    )?;
+
+    let path = Path::new(&options.arguments[1]);
+    let manifest_path = path.with_extension("txt");
+    let name = path
+        .file_name()
+        .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+    fs::write(path, syx::to_bytes(&messages))
+        .map_err(|error| Failure::file(&options.arguments[1], error))?;
+    fs::write(
+        &manifest_path,
+        syx::manifest(&messages, &name, &options.target),
+    )
+    .map_err(|error| Failure::file(&manifest_path.to_string_lossy(), error))?;
+
+    println!(
+        "wrote {} messages to {} and their delays to {}, about {:.1} s to send",
+        messages.len(),
+        path.display(),
+        manifest_path.display(),
+        syx::duration(&messages).as_secs_f64()
+    );
+    Ok(())
+}
+
+fn trace(options: &Options) -> Result<(), Failure> {
+    let capture = load_capture(&options.arguments[0])?;
+    for (index, entry) in trace::decode(&capture, &options.target).iter().enumerate() {
+        println!("{:4} {}", index + 1, entry);
+    }
+    Ok(())
+}
+
+fn load_capture(path: &str) -> Result<Vec<u8>, Failure> {
+    fs::read(path).map_err(|error| Failure::file(path, error))
+}
+
+// Intel HEX is recognised by its extension, anything else is a raw binary
+// starting at address 0.
+fn load_image(path: &str) -> Result<Image, Failure> {
+    if is_ihex(path) {
+        let text = fs::read_to_string(path).map_err(|error| Failure::file(path, error))?;
+        ihex::parse(&text).map_err(|error| Failure::file(path, error))
+    } else {
+        let binary = fs::read(path).map_err(|error| Failure::file(path, error))?;
+        Ok(Image::from_binary(&binary))
+    }
+}
+
+fn save_image(path: &str, image: &Image) -> Result<(), Failure> {
+    let result = if is_ihex(path) {
+        fs::write(path, ihex::to_string(image))
+    } else {
+        fs::write(path, image.to_binary())
+    };
+    result.map_err(|error| Failure::file(path, error))
+}
+
+fn is_ihex(path: &str) -> bool {
+    match Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some(extension) => ["hex", "ihx", "ihex"].contains(&extension.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+fn check_report(report: Report) -> Result<(), Failure> {
+    match report.failed.into_iter().next() {
+        Some((_, error)) => Err(Failure::from(error)),
+        None => Ok(()),
+    }
+}
+
+fn print_report(report: &Report) {
+    println!(
+        "{} pages written, {} unchanged, {} blank, {} retried",
+        report.written.len(),
+        report.unchanged.len(),
+        report.skipped.len(),
+        report.retried.len()
+    );
+}
+
+// CRC-16 over the application section with missing bytes counted as
+// erased. After a flash or verify it is computed from the image file alone
+// and only describes the device where the image covers every page.
+fn print_crc(options: &Options, source: &str, image: &Image) {
+    let boot_page = options.target.boot_page();
+    println!(
+        "{} crc 0x{:04x} over pages 0 to {}",
+        source,
+        image.crc16(&options.target, 0..boot_page),
+        boot_page - 1
+    );
+}
+
+fn print_progress(progress: &Progress) {
+    let (action, done, total) = match *progress {
+        Progress::Compared { done, total, .. } => ("comparing", done, total),
+        Progress::Written { done, total, .. } => ("writing", done, total),
+        Progress::Verified { done, total, .. } => ("verifying", done, total),
+        Progress::Read { done, total, .. } => ("reading", done, total),
+        Progress::Retrying { page_no } => {
+            eprintln!("\nretrying page {}", page_no);
+            return;
+        }
+        Progress::Pinged | Progress::Quit => return,
+    };
+    eprint!("\r{} page {}/{}", action, done, total);
+    if done == total {
+        eprintln!();
+    }
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(digits) => usize::from_str_radix(digits, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sysexprog::protocol::PROTOCOL_V3;
+    use sysexprog::target::ATMEGA32;
+
+    fn parse(args: &[&str]) -> Result<Options, Failure> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn usage_error(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("{:?} parsed", args),
+            Err(failure) => {
+                assert_eq!(failure.code, EXIT_USAGE);
+                failure.message
+            }
+        }
+    }
+
+    #[test]
+    fn parses_options_anywhere() {
+        let options = match parse(&[
+            "-t",
+            "atmega32",
+            "flash",
+            "--protocol",
+            "3",
+            "app.hex",
+            "--hfuse",
+            "0x99",
+            "--incremental",
+            "-n",
+        ]) {
+            Ok(options) => options,
+            Err(failure) => panic!("{}", failure.message),
+        };
+        assert_eq!(options.command, "flash");
+        assert_eq!(options.arguments, vec!["app.hex"]);
+        assert_eq!(options.target, ATMEGA32);
+        assert_eq!(options.protocol, Some(PROTOCOL_V3));
+        assert_eq!(options.high_fuse, Some(0x99));
+        assert!(options.incremental && options.dry_run);
+        assert!(!options.write_blank && !options.all && !options.replay);
+    }
+
+    #[test]
+    fn help_wins_over_the_command() {
+        match parse(&["flash", "app.hex", "-h"]) {
+            Ok(options) => assert_eq!(options.command, "help"),
+            Err(failure) => panic!("{}", failure.message),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_usage() {
+        assert_eq!(usage_error(&[]), "no command given");
+        assert_eq!(usage_error(&["erase"]), "unknown command 'erase'");
+        assert_eq!(
+            usage_error(&["--force", "ping"]),
+            "unknown option '--force'"
+        );
+        assert_eq!(usage_error(&["ping", "-p"]), "-p needs a value");
+        assert_eq!(
+            usage_error(&["-t", "atmega8", "ping"]),
+            "unknown target 'atmega8'"
+        );
+        assert_eq!(
+            usage_error(&["--protocol", "0x105", "ping"]),
+            "unknown protocol version '0x105'"
+        );
+        assert_eq!(
+            usage_error(&["--hfuse", "256", "ping"]),
+            "invalid fuse value '256'"
+        );
+        assert_eq!(
+            usage_error(&["export", "app.hex"]),
+            "export takes 2 argument(s)"
+        );
+    }
 }
@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use command::Request;
+use error::{BootloaderError, Error, TransportError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandPolicy {
+    pub timeout: Duration,
+    pub retries: usize,
+}
+
+impl CommandPolicy {
+    pub fn new(timeout_ms: u64, retries: usize) -> CommandPolicy {
+        CommandPolicy {
+            timeout: Duration::from_millis(timeout_ms),
+            retries,
+        }
+    }
+}
+
+// The bootloader answers every message exactly once and buffers nothing, so
+// a lost reply can only be detected by a timeout. Writes erase and refill the
+// whole page and are safe to repeat, as are reads and verifies. Quit is only
+// repeated after a ping shows the bootloader is still listening.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub ping: CommandPolicy,
+    pub write: CommandPolicy,
+    pub read: CommandPolicy,
+    pub verify: CommandPolicy,
+    pub quit: CommandPolicy,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            ping: CommandPolicy::new(250, 2),
+            write: CommandPolicy::new(1000, 3),
+            read: CommandPolicy::new(1000, 5),
+            verify: CommandPolicy::new(250, 5),
+            quit: CommandPolicy::new(250, 2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn no_retries() -> RetryPolicy {
+        let policy = RetryPolicy::default();
+        RetryPolicy {
+            ping: CommandPolicy {
+                retries: 0,
+                ..policy.ping
+            },
+            write: CommandPolicy {
+                retries: 0,
+                ..policy.write
+            },
+            read: CommandPolicy {
+                retries: 0,
+                ..policy.read
+            },
+            verify: CommandPolicy {
+                retries: 0,
+                ..policy.verify
+            },
+            quit: CommandPolicy {
+                retries: 0,
+                ..policy.quit
+            },
+        }
+    }
+
+    pub fn for_request(&self, request: &Request) -> CommandPolicy {
+        match *request {
+            Request::Ping(_) => self.ping,
+            Request::Write(_) => self.write,
+            Request::Read(_) => self.read,
+            Request::Verify(_) | Request::VerifyRange(_) => self.verify,
+            Request::Quit(_) => self.quit,
+        }
+    }
+}
+
+// Whether the error may be caused by a glitch on the link, as opposed to a
+// command the bootloader will refuse no matter how often it is sent.
+pub fn is_transient(error: &Error) -> bool {
+    match *error {
+        Error::Transport(TransportError::Timeout)
+        | Error::Transport(TransportError::Truncated(_))
+        | Error::Decode(_)
+        | Error::UnexpectedReply(_) => true,
+        Error::Bootloader(error) => !matches!(
+            error,
+            BootloaderError::UnknownCommand | BootloaderError::InvalidPageNumber
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::{CommandError, DecodeError};
+    use protocol::Checksum;
+    use reply::Reply;
+    use std::io;
+
+    #[test]
+    fn link_glitches_are_transient() {
+        for error in [
+            Error::Transport(TransportError::Timeout),
+            Error::Transport(TransportError::Truncated(vec![0xf0, 0x00])),
+            Error::Decode(DecodeError::InvalidChecksum),
+            Error::UnexpectedReply(Reply::Success),
+            Error::Bootloader(BootloaderError::InvalidNibble),
+            Error::Bootloader(BootloaderError::InvalidChecksum),
+            Error::Bootloader(BootloaderError::IncompleteMessage),
+        ] {
+            assert!(is_transient(&error), "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn refusals_are_not_transient() {
+        for error in [
+            Error::Transport(TransportError::Io(io::ErrorKind::UnexpectedEof.into())),
+            Error::Transport(TransportError::Backend("unplugged".to_string())),
+            Error::Bootloader(BootloaderError::UnknownCommand),
+            Error::Bootloader(BootloaderError::InvalidPageNumber),
+            Error::Command(CommandError::InvalidPageNumber {
+                page_no: 128,
+                num_pages: 128,
+            }),
+            Error::VerifyMismatch {
+                page_no: 1,
+                expected: Checksum::Xor(1),
+                actual: Checksum::Xor(2),
+            },
+            Error::NotErased { page_no: 1 },
+        ] {
+            assert!(!is_transient(&error), "{:?}", error);
+        }
+    }
+}
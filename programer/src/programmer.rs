@@ -0,0 +1,606 @@
+use command::Write;
+use error::Error;
+use image::{Image, ERASED};
+use policy;
+use session::Session;
+use transport::Transport;
+
+const DEFAULT_PAGE_RETRIES: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    Pinged,
+    Compared {
+        page_no: usize,
+        unchanged: bool,
+        done: usize,
+        total: usize,
+    },
+    Written {
+        page_no: usize,
+        done: usize,
+        total: usize,
+    },
+    Verified {
+        page_no: usize,
+        done: usize,
+        total: usize,
+    },
+    Read {
+        page_no: usize,
+        done: usize,
+        total: usize,
+    },
+    Retrying {
+        page_no: usize,
+    },
+    Quit,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub written: Vec<usize>,
+    pub skipped: Vec<usize>,
+    pub unchanged: Vec<usize>,
+    pub retried: Vec<usize>,
+    pub failed: Vec<(usize, Error)>,
+}
+
+impl Report {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+// Runs a whole flashing job: ping, write every page, verify every page
+// against its local checksum and finally jump to the application. Page 0 and
+// its reset vector are written and verified last, and only if every other
+// page made it. Blank pages are not written by default, but read back and
+// only written if the device does not hold them erased.
+//
+// In incremental mode every page is compared with the device first and only
+// pages that really differ are written, which is much faster over MIDI and
+// spares the flash when a board is reflashed with a mostly unchanged image.
+pub struct Programmer<'a, T: Transport> {
+    session: Session<T>,
+    skip_blank: bool,
+    incremental: bool,
+    page_retries: usize,
+    progress: Box<dyn FnMut(&Progress) + 'a>,
+}
+
+impl<'a, T: Transport> Programmer<'a, T> {
+    pub fn new(session: Session<T>) -> Programmer<'a, T> {
+        Programmer {
+            session,
+            skip_blank: true,
+            incremental: false,
+            page_retries: DEFAULT_PAGE_RETRIES,
+            progress: Box::new(|_| {}),
+        }
+    }
+
+    pub fn with_skip_blank(mut self, skip_blank: bool) -> Programmer<'a, T> {
+        self.skip_blank = skip_blank;
+        self
+    }
+
+    pub fn with_incremental(mut self, incremental: bool) -> Programmer<'a, T> {
+        self.incremental = incremental;
+        self
+    }
+
+    pub fn with_page_retries(mut self, page_retries: usize) -> Programmer<'a, T> {
+        self.page_retries = page_retries;
+        self
+    }
+
+    pub fn on_progress<F: FnMut(&Progress) + 'a>(mut self, progress: F) -> Programmer<'a, T> {
+        self.progress = Box::new(progress);
+        self
+    }
+
+    pub fn session(&mut self) -> &mut Session<T> {
+        &mut self.session
+    }
+
+    pub fn into_session(self) -> Session<T> {
+        self.session
+    }
+
+    pub fn flash(&mut self, image: &Image) -> Result<Report, Error> {
+        let writes = self.plan(image)?;
+        let skip_blank = self.skip_blank;
+        let mut report = Report {
+            skipped: writes
+                .iter()
+                .filter(|write| skip_blank && is_blank(write))
+                .map(|write| write.page_no() as usize)
+                .collect(),
+            ..Report::default()
+        };
+        let pending: Vec<&Write> = writes
+            .iter()
+            .filter(|write| !report.skipped.contains(&(write.page_no() as usize)))
+            .collect();
+
+        self.session.ping()?;
+        (self.progress)(&Progress::Pinged);
+
+        let pending = if self.incremental {
+            let total = pending.len();
+            let mut changed = Vec::new();
+            for (done, write) in pending.into_iter().enumerate() {
+                let page_no = write.page_no() as usize;
+                let unchanged = match self
+                    .retry(page_no, &mut report, |session| is_unchanged(session, write))
+                {
+                    Ok(unchanged) => unchanged,
+                    Err(error) => {
+                        report.failed.push((page_no, error));
+                        return Ok(report);
+                    }
+                };
+                if unchanged {
+                    report.unchanged.push(page_no);
+                } else {
+                    changed.push(write);
+                }
+                (self.progress)(&Progress::Compared {
+                    page_no,
+                    unchanged,
+                    done: done + 1,
+                    total,
+                });
+            }
+            changed
+        } else {
+            pending
+        };
+
+        // Page 0 holds the reset vector, so it is only written once every
+        // other page is in place and verified.
+        let in_stage = |write: &Write, last: bool| (write.page_no() == 0) == last;
+        let write_total = pending.len();
+        let check_total = pending.len() + report.skipped.len();
+        let (mut write_done, mut check_done) = (0, 0);
+        for &last in &[false, true] {
+            for &write in pending.iter().filter(|write| in_stage(write, last)) {
+                let page_no = write.page_no() as usize;
+                if let Err(error) = self.retry(page_no, &mut report, |session| session.write(write))
+                {
+                    report.failed.push((page_no, error));
+                    return Ok(report);
+                }
+                report.written.push(page_no);
+                write_done += 1;
+                (self.progress)(&Progress::Written {
+                    page_no,
+                    done: write_done,
+                    total: write_total,
+                });
+            }
+
+            // The pages just written and the skipped blank ones, which may
+            // still hold an older application.
+            let checked: Vec<&Write> = writes
+                .iter()
+                .filter(|write| {
+                    let page_no = write.page_no() as usize;
+                    in_stage(write, last)
+                        && (report.written.contains(&page_no) || report.skipped.contains(&page_no))
+                })
+                .collect();
+            let confirmed = self.verify_runs(&checked);
+            for &write in &checked {
+                let page_no = write.page_no() as usize;
+                check_done += 1;
+                let expected = self
+                    .session
+                    .protocol()
+                    .integrity
+                    .checksum(write.page_data());
+                let result = if confirmed.contains(&page_no) {
+                    Ok(false)
+                } else if report.skipped.contains(&page_no) {
+                    self.retry(page_no, &mut report, |session| {
+                        if is_erased(session, page_no)? {
+                            return Ok(false);
+                        }
+                        session.write(write)?;
+                        if is_erased(session, page_no)? {
+                            Ok(true)
+                        } else {
+                            Err(Error::NotErased { page_no })
+                        }
+                    })
+                } else {
+                    self.retry(page_no, &mut report, |session| {
+                        if session.verify(page_no)? == expected {
+                            return Ok(false);
+                        }
+                        session.write(write)?;
+                        match session.verify(page_no)? {
+                            checksum if checksum == expected => Ok(true),
+                            checksum => Err(Error::VerifyMismatch {
+                                page_no,
+                                expected,
+                                actual: checksum,
+                            }),
+                        }
+                    })
+                };
+                match result {
+                    Ok(true) if !report.written.contains(&page_no) => {
+                        report.skipped.retain(|&skipped| skipped != page_no);
+                        report.written.push(page_no);
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        report.failed.push((page_no, error));
+                        continue;
+                    }
+                }
+                (self.progress)(&Progress::Verified {
+                    page_no,
+                    done: check_done,
+                    total: check_total,
+                });
+            }
+            if !report.is_success() {
+                return Ok(report);
+            }
+        }
+
+        self.session.quit()?;
+        (self.progress)(&Progress::Quit);
+        Ok(report)
+    }
+
+    // Compares every page of the image with the device by checksum, blank
+    // ones included, without writing anything or leaving the bootloader.
+    pub fn verify(&mut self, image: &Image) -> Result<Report, Error> {
+        let writes = self.plan(image)?;
+        let mut report = Report::default();
+
+        self.session.ping()?;
+        (self.progress)(&Progress::Pinged);
+
+        let confirmed = self.verify_runs(&writes.iter().collect::<Vec<_>>());
+        let total = writes.len();
+        for (done, write) in writes.iter().enumerate() {
+            let page_no = write.page_no() as usize;
+            let expected = self
+                .session
+                .protocol()
+                .integrity
+                .checksum(write.page_data());
+            if confirmed.contains(&page_no) {
+                (self.progress)(&Progress::Verified {
+                    page_no,
+                    done: done + 1,
+                    total,
+                });
+                continue;
+            }
+            if is_blank(write) {
+                match self.retry(page_no, &mut report, |session| is_erased(session, page_no)) {
+                    Ok(true) => {}
+                    Ok(false) => report.failed.push((page_no, Error::NotErased { page_no })),
+                    Err(error) => report.failed.push((page_no, error)),
+                }
+                (self.progress)(&Progress::Verified {
+                    page_no,
+                    done: done + 1,
+                    total,
+                });
+                continue;
+            }
+            match self.retry(page_no, &mut report, |session| session.verify(page_no)) {
+                Ok(checksum) if checksum == expected => {}
+                Ok(checksum) => report.failed.push((
+                    page_no,
+                    Error::VerifyMismatch {
+                        page_no,
+                        expected,
+                        actual: checksum,
+                    },
+                )),
+                Err(error) => report.failed.push((page_no, error)),
+            }
+            (self.progress)(&Progress::Verified {
+                page_no,
+                done: done + 1,
+                total,
+            });
+        }
+        Ok(report)
+    }
+
+    // Reads the flash back page by page, optionally stopping short of the
+    // bootloader section.
+    pub fn read_flash(&mut self, include_bootloader: bool) -> Result<Image, Error> {
+        let target = *self.session.target();
+        let total = if include_bootloader {
+            target.num_pages()
+        } else {
+            target.boot_page()
+        };
+
+        self.session.ping()?;
+        (self.progress)(&Progress::Pinged);
+
+        let mut image = Image::new();
+        for page_no in 0..total {
+            let page_data = self.session.read(page_no)?;
+            for (offset, &byte) in page_data.iter().enumerate() {
+                image.set(page_no * target.page_size + offset, byte);
+            }
+            (self.progress)(&Progress::Read {
+                page_no,
+                done: page_no + 1,
+                total,
+            });
+        }
+        Ok(image)
+    }
+
+    // Checks every run of consecutive pages with a single VerifyRange where
+    // the bootloader supports it and returns the pages found intact. Pages of
+    // a run that does not match, or could not be checked, are left to the
+    // page by page verify, which tells which of them is broken.
+    fn verify_runs(&mut self, writes: &[&Write]) -> Vec<usize> {
+        let mut confirmed = Vec::new();
+        if !self.session.protocol().verify_range {
+            return confirmed;
+        }
+        let integrity = self.session.protocol().integrity;
+
+        let mut writes = writes.to_vec();
+        writes.sort_by_key(|write| write.page_no());
+        let mut start = 0;
+        while start < writes.len() {
+            let first_page = writes[start].page_no() as usize;
+            let mut end = start + 1;
+            while end < writes.len() && writes[end].page_no() as usize == first_page + end - start {
+                end += 1;
+            }
+            let run = &writes[start..end];
+            start = end;
+
+            let data: Vec<u8> = run
+                .iter()
+                .flat_map(|write| write.page_data().iter().cloned())
+                .collect();
+            let last_page = first_page + run.len() - 1;
+            if let Ok(checksum) = self.session.verify_range(first_page, last_page) {
+                if checksum == integrity.checksum(&data) {
+                    confirmed.extend(first_page..=last_page);
+                }
+            }
+        }
+        confirmed
+    }
+
+    // Every page of the image, blank or not, with page 0 last.
+    fn plan(&self, image: &Image) -> Result<Vec<Write>, Error> {
+        let target = *self.session.target();
+        let protection = *self.session.protection();
+        Ok(image.to_writes(&target, &protection, false)?)
+    }
+
+    fn retry<R, F>(
+        &mut self,
+        page_no: usize,
+        report: &mut Report,
+        mut operation: F,
+    ) -> Result<R, Error>
+    where
+        F: FnMut(&mut Session<T>) -> Result<R, Error>,
+    {
+        let retries = self.session.retries();
+        let mut attempt = 0;
+        let result = loop {
+            let error = match operation(&mut self.session) {
+                Ok(result) => break Ok(result),
+                Err(error) => error,
+            };
+            if attempt == self.page_retries || !is_retryable(&error) {
+                break Err(error);
+            }
+            attempt += 1;
+            (self.progress)(&Progress::Retrying { page_no });
+        };
+
+        if (attempt > 0 || self.session.retries() > retries) && !report.retried.contains(&page_no) {
+            report.retried.push(page_no);
+        }
+        result
+    }
+}
+
+// Not even a CRC tells every change apart, so a matching page is read back
+// and compared byte for byte before it is skipped.
+fn is_unchanged<T: Transport>(session: &mut Session<T>, write: &Write) -> Result<bool, Error> {
+    let page_no = write.page_no() as usize;
+    let expected = session.protocol().integrity.checksum(write.page_data());
+    if session.verify(page_no)? != expected {
+        return Ok(false);
+    }
+    Ok(session.read(page_no)? == write.page_data())
+}
+
+fn is_blank(write: &Write) -> bool {
+    write.page_data().iter().all(|&byte| byte == ERASED)
+}
+
+// Blank pages are read back rather than verified, since an XOR cannot tell
+// an erased page from one filled with any other byte value.
+fn is_erased<T: Transport>(session: &mut Session<T>, page_no: usize) -> Result<bool, Error> {
+    Ok(session.read(page_no)?.iter().all(|&byte| byte == ERASED))
+}
+
+// Transient errors have already been retried by the session, this covers
+// whole page operations such as rewriting a page that failed to verify.
+fn is_retryable(error: &Error) -> bool {
+    match *error {
+        Error::VerifyMismatch { .. } | Error::NotErased { .. } => true,
+        ref error => policy::is_transient(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::Request;
+    use error::TransportError;
+    use std::time::Duration;
+    use target::ATMEGA16;
+    use transport::simulator::Simulator;
+
+    // Passes everything on to the simulator and notes the pages written, in
+    // the order they went out.
+    struct Recorder<'a> {
+        simulator: &'a mut Simulator,
+        writes: Vec<u8>,
+    }
+
+    impl<'a> Transport for Recorder<'a> {
+        fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+            if let Ok(Request::Write(write)) = Request::from_sysex(message, &ATMEGA16) {
+                self.writes.push(write.page_no());
+            }
+            self.simulator.send(message)
+        }
+
+        fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+            self.simulator.receive(timeout)
+        }
+    }
+
+    // Pages 0 to 7 with page 5 explicitly blank.
+    fn image_with_blank_page() -> Image {
+        let mut image = Image::new();
+        for address in 0..8 * ATMEGA16.page_size {
+            image.set(address, address as u8 & 0x7f);
+        }
+        for address in 5 * ATMEGA16.page_size..6 * ATMEGA16.page_size {
+            image.set(address, ERASED);
+        }
+        image
+    }
+
+    fn simulator_with_old_page() -> Simulator {
+        let mut simulator = Simulator::new(ATMEGA16);
+        for byte in &mut simulator.flash_mut()[5 * ATMEGA16.page_size..6 * ATMEGA16.page_size] {
+            *byte = 0x42;
+        }
+        simulator
+    }
+
+    #[test]
+    fn verify_checks_blank_pages() {
+        let image = image_with_blank_page();
+        let mut simulator = simulator_with_old_page();
+        for page_no in (0..8).filter(|&page_no| page_no != 5) {
+            let page = page_no * ATMEGA16.page_size..(page_no + 1) * ATMEGA16.page_size;
+            simulator.flash_mut()[page.clone()].copy_from_slice(&image.to_binary()[page]);
+        }
+        let report = Programmer::new(Session::new(&mut simulator, ATMEGA16))
+            .verify(&image)
+            .unwrap();
+        let failed: Vec<usize> = report.failed.iter().map(|&(page_no, _)| page_no).collect();
+        assert_eq!(failed, vec![5]);
+    }
+
+    #[test]
+    fn flash_erases_skipped_blank_page_that_is_not_blank() {
+        let mut simulator = simulator_with_old_page();
+        let image = image_with_blank_page();
+        let mut recorder = Recorder {
+            simulator: &mut simulator,
+            writes: Vec::new(),
+        };
+        let report = Programmer::new(Session::new(&mut recorder, ATMEGA16))
+            .flash(&image)
+            .unwrap();
+        assert!(report.is_success());
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.written, vec![1, 2, 3, 4, 6, 7, 5, 0]);
+        assert_eq!(recorder.writes, vec![1, 2, 3, 4, 6, 7, 5, 0]);
+        assert_eq!(
+            simulator.flash()[..8 * ATMEGA16.page_size],
+            image.to_binary()[..]
+        );
+
+        simulator.reset();
+        let report = Programmer::new(Session::new(&mut simulator, ATMEGA16))
+            .flash(&image)
+            .unwrap();
+        assert_eq!(report.skipped, vec![5]);
+        assert!(!report.written.contains(&5));
+    }
+
+    #[test]
+    fn flash_writes_blank_pages_unless_skipped() {
+        let mut simulator = simulator_with_old_page();
+        let image = image_with_blank_page();
+        let report = Programmer::new(Session::new(&mut simulator, ATMEGA16))
+            .with_skip_blank(false)
+            .flash(&image)
+            .unwrap();
+        assert!(report.is_success());
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.written, vec![1, 2, 3, 4, 5, 6, 7, 0]);
+        assert_eq!(
+            simulator.flash()[..8 * ATMEGA16.page_size],
+            image.to_binary()[..]
+        );
+    }
+
+    #[test]
+    fn read_flash_stops_short_of_the_bootloader() {
+        let mut simulator = Simulator::new(ATMEGA16);
+        simulator.flash_mut()[0] = 0x42;
+        for &(include_bootloader, num_pages) in &[(true, 128), (false, 120)] {
+            let mut pages = Vec::new();
+            let image = Programmer::new(Session::new(&mut simulator, ATMEGA16))
+                .on_progress(|progress| {
+                    if let Progress::Read { page_no, .. } = *progress {
+                        pages.push(page_no);
+                    }
+                })
+                .read_flash(include_bootloader)
+                .unwrap();
+            assert_eq!(pages, (0..num_pages).collect::<Vec<_>>());
+            assert_eq!(image.len(), num_pages * ATMEGA16.page_size);
+            assert_eq!(image.get(0), Some(0x42));
+        }
+    }
+
+    #[test]
+    fn incremental_flash_writes_only_changed_pages() {
+        let image = image_with_blank_page();
+        let page_size = ATMEGA16.page_size;
+        let mut simulator = Simulator::new(ATMEGA16);
+        simulator.flash_mut()[..8 * page_size].copy_from_slice(&image.to_binary());
+        // Page 2 differs outright, page 3 only by two swapped bytes, which
+        // leaves its XOR checksum unchanged.
+        simulator.flash_mut()[2 * page_size] ^= 0x01;
+        simulator.flash_mut().swap(3 * page_size, 3 * page_size + 1);
+        let mut recorder = Recorder {
+            simulator: &mut simulator,
+            writes: Vec::new(),
+        };
+        let report = Programmer::new(Session::new(&mut recorder, ATMEGA16))
+            .with_incremental(true)
+            .flash(&image)
+            .unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.unchanged, vec![1, 4, 6, 7, 0]);
+        assert_eq!(report.written, vec![2, 3]);
+        assert_eq!(report.skipped, vec![5]);
+        assert_eq!(recorder.writes, vec![2, 3]);
+        assert_eq!(simulator.flash()[..8 * page_size], image.to_binary()[..]);
+    }
+}
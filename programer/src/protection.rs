@@ -0,0 +1,97 @@
+use error::CommandError;
+use target::Target;
+
+// The bootloader itself only checks page numbers against the size of the
+// whole flash, so it will happily overwrite its own section. Every image and
+// command goes through this policy before anything is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    first_protected: usize,
+}
+
+impl Protection {
+    pub fn new(target: &Target) -> Protection {
+        Protection {
+            first_protected: target.boot_page(),
+        }
+    }
+
+    // Also covers the boot section configured by the BOOTSZ fuses, in case it
+    // is larger than the one the bootloader was linked for.
+    pub fn with_high_fuse(target: &Target, high_fuse: u8) -> Protection {
+        let boot_start = target.flash_size - target.boot_size(high_fuse);
+        Protection {
+            first_protected: target.page_of(boot_start.min(target.boot_start)),
+        }
+    }
+
+    // Only for re-flashing the bootloader itself. A failed write with this
+    // policy leaves a board that can only be recovered with an ISP cable.
+    pub fn dangerously_allow_bootloader_overwrite(target: &Target) -> Protection {
+        Protection {
+            first_protected: target.num_pages(),
+        }
+    }
+
+    pub fn first_protected(&self) -> usize {
+        self.first_protected
+    }
+
+    pub fn check_page(&self, page_no: usize) -> Result<(), CommandError> {
+        if page_no >= self.first_protected {
+            return Err(CommandError::ProtectedPage {
+                page_no,
+                first_protected: self.first_protected,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use target::{ATMEGA16, ATMEGA644P};
+
+    #[test]
+    fn protects_the_bootloader_section() {
+        let protection = Protection::new(&ATMEGA16);
+        assert_eq!(protection.first_protected(), 120);
+        assert_eq!(protection.check_page(119), Ok(()));
+        assert_eq!(
+            protection.check_page(120),
+            Err(CommandError::ProtectedPage {
+                page_no: 120,
+                first_protected: 120,
+            })
+        );
+    }
+
+    #[test]
+    fn high_fuse_widens_protection() {
+        // BOOTSZ1:0 = 00 reserves 2 KiB, more than the bootloader needs.
+        assert_eq!(
+            Protection::with_high_fuse(&ATMEGA16, 0x99).first_protected(),
+            112
+        );
+        assert_eq!(
+            Protection::with_high_fuse(&ATMEGA16, 0xfb).first_protected(),
+            120
+        );
+        // A smaller fuse setting never exposes the linked bootloader.
+        assert_eq!(
+            Protection::with_high_fuse(&ATMEGA16, 0xff).first_protected(),
+            120
+        );
+        assert_eq!(
+            Protection::with_high_fuse(&ATMEGA644P, 0xf9).first_protected(),
+            224
+        );
+    }
+
+    #[test]
+    fn overwrite_allows_every_page() {
+        let protection = Protection::dangerously_allow_bootloader_overwrite(&ATMEGA16);
+        assert_eq!(protection.check_page(127), Ok(()));
+    }
+}
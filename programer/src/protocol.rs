@@ -0,0 +1,240 @@
+use std::fmt;
+
+use command;
+use error::DecodeError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    // Every byte split into two data bytes, high nibble first.
+    Nibbles,
+    // Groups of up to seven bytes, each led by a data byte that carries
+    // their top bits, bit 0 for the first byte of the group.
+    Packed,
+}
+
+impl Encoding {
+    pub fn encode(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Nibbles => bytes
+                .iter()
+                .flat_map(|byte| vec![byte >> 4, byte & 0x0f])
+                .collect(),
+            Encoding::Packed => bytes
+                .chunks(7)
+                .flat_map(|group| {
+                    let high_bits = group
+                        .iter()
+                        .enumerate()
+                        .fold(0, |bits, (index, byte)| bits | (byte >> 7) << index);
+                    let mut packed = vec![high_bits];
+                    packed.extend(group.iter().map(|byte| byte & 0x7f));
+                    packed
+                })
+                .collect(),
+        }
+    }
+
+    pub fn decode(self, data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        match self {
+            Encoding::Nibbles => command::from_nibbles(data),
+            Encoding::Packed => {
+                let mut bytes = Vec::new();
+                for group in data.chunks(8) {
+                    if group.iter().any(|&byte| byte > 0x7f) {
+                        return Err(DecodeError::InvalidFormat);
+                    }
+                    let (high_bits, group) = (group[0], &group[1..]);
+                    if group.is_empty() || high_bits >> group.len() != 0 {
+                        return Err(DecodeError::InvalidFormat);
+                    }
+                    for (index, &byte) in group.iter().enumerate() {
+                        bytes.push(byte | (high_bits >> index & 1) << 7);
+                    }
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+// How messages and flash pages are checked, from the trailing checksum of
+// every message to the value a verify reply carries for a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Integrity {
+    // A single byte XOR, blind to swapped bytes and to the same bit flipped
+    // twice.
+    Xor,
+    // CRC-16/CCITT, sent big-endian.
+    Crc16,
+}
+
+impl Integrity {
+    pub fn checksum(self, bytes: &[u8]) -> Checksum {
+        match self {
+            Integrity::Xor => Checksum::Xor(command::checksum(bytes)),
+            Integrity::Crc16 => Checksum::Crc16(command::crc16(bytes)),
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            Integrity::Xor => 1,
+            Integrity::Crc16 => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    Xor(u8),
+    Crc16(u16),
+}
+
+impl Checksum {
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self {
+            Checksum::Xor(checksum) => vec![checksum],
+            Checksum::Crc16(crc) => vec![(crc >> 8) as u8, crc as u8],
+        }
+    }
+
+    // The kind of checksum is told apart by its size alone.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Checksum> {
+        match *bytes {
+            [checksum] => Some(Checksum::Xor(checksum)),
+            [high, low] => Some(Checksum::Crc16(u16::from(high) << 8 | u16::from(low))),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Checksum::Xor(checksum) => write!(f, "0x{:02x}", checksum),
+            Checksum::Crc16(crc) => write!(f, "crc 0x{:04x}", crc),
+        }
+    }
+}
+
+// A revision of the sysex protocol, announced by the VERSION byte that
+// closes the header of every message in either direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: u8,
+    pub encoding: Encoding,
+    pub integrity: Integrity,
+    // Whether the bootloader understands VerifyRange.
+    pub verify_range: bool,
+}
+
+// Spoken by firmware/bootloader.cpp and every board shipped since 2016.
+pub const PROTOCOL_V1: Protocol = Protocol {
+    version: 0x01,
+    encoding: Encoding::Nibbles,
+    integrity: Integrity::Xor,
+    verify_range: false,
+};
+
+// Versions 0x02 to 0x04 are drafts modelled by the host and the simulator
+// only. No bootloader implements them yet, so negotiating with a board
+// running firmware/bootloader.cpp settles on version 0x01.
+
+// Draft. Cuts a page write from 262 to 150 data bytes.
+pub const PROTOCOL_V2: Protocol = Protocol {
+    version: 0x02,
+    encoding: Encoding::Packed,
+    integrity: Integrity::Xor,
+    verify_range: false,
+};
+
+// Draft. Packed like version 0x02, but every message and page verify is
+// checked with a CRC instead of the XOR.
+pub const PROTOCOL_V3: Protocol = Protocol {
+    version: 0x03,
+    encoding: Encoding::Packed,
+    integrity: Integrity::Crc16,
+    verify_range: false,
+};
+
+// Draft. Adds VerifyRange, answered with a CRC over all pages of the range.
+pub const PROTOCOL_V4: Protocol = Protocol {
+    version: 0x04,
+    encoding: Encoding::Packed,
+    integrity: Integrity::Crc16,
+    verify_range: true,
+};
+
+// Oldest first.
+pub static PROTOCOLS: [Protocol; 4] = [PROTOCOL_V1, PROTOCOL_V2, PROTOCOL_V3, PROTOCOL_V4];
+
+impl Protocol {
+    pub fn by_version(version: u8) -> Option<&'static Protocol> {
+        PROTOCOLS
+            .iter()
+            .find(|protocol| protocol.version == version)
+    }
+
+    pub fn header(&self) -> [u8; 4] {
+        let mut header = command::HEADER;
+        header[header.len() - 1] = self.version;
+        header
+    }
+}
+
+impl Default for Protocol {
+    fn default() -> Protocol {
+        PROTOCOL_V1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_groups() {
+        let bytes = [0x80, 0x01, 0x7f, 0xff, 0x00, 0x12, 0x34, 0xfe];
+        let data = Encoding::Packed.encode(&bytes);
+        assert_eq!(
+            data,
+            vec![0x09, 0x00, 0x01, 0x7f, 0x7f, 0x00, 0x12, 0x34, 0x01, 0x7e]
+        );
+        for len in 0..40 {
+            let bytes: Vec<u8> = (0..len).map(|byte| (byte * 37) as u8).collect();
+            assert_eq!(
+                Encoding::Packed.decode(&Encoding::Packed.encode(&bytes)),
+                Ok(bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn packed_rejects_malformed_data() {
+        for data in &[
+            &[0x00, 0x90][..],
+            &[0x80, 0x10],
+            &[0x00],
+            &[0x02, 0x10],
+            &[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x00],
+        ] {
+            assert_eq!(
+                Encoding::Packed.decode(data),
+                Err(DecodeError::InvalidFormat)
+            );
+        }
+    }
+
+    #[test]
+    fn checksum_bytes() {
+        for &integrity in &[Integrity::Xor, Integrity::Crc16] {
+            let checksum = integrity.checksum(b"123456789");
+            assert_eq!(checksum.to_bytes().len(), integrity.size());
+            assert_eq!(Checksum::from_bytes(&checksum.to_bytes()), Some(checksum));
+        }
+        assert_eq!(
+            Integrity::Crc16.checksum(b"123456789"),
+            Checksum::Crc16(0x29b1)
+        );
+    }
+}
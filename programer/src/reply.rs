@@ -1 +1,126 @@
+use std::fmt;
 
+use command::{self, Command};
+use error::{BootloaderError, DecodeError};
+use protocol::Checksum;
+
+const REPLY_SUCCESS: u8 = 0x20;
+const REPLY_ERROR: u8 = 0x21;
+const REPLY_READ: u8 = 0x22;
+const REPLY_VERIFY: u8 = 0x23;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Success,
+    Error(BootloaderError),
+    Read(Vec<u8>),
+    Verify(Checksum),
+}
+
+impl Reply {
+    pub fn from_sysex(message: &[u8]) -> Result<Reply, DecodeError> {
+        let payload = command::from_sysex(message)?;
+        let (&kind, params) = payload.split_first().ok_or(DecodeError::InvalidFormat)?;
+        match kind {
+            REPLY_SUCCESS if params.is_empty() => Ok(Reply::Success),
+            REPLY_ERROR if params.len() == 1 => BootloaderError::from_code(params[0])
+                .map(Reply::Error)
+                .ok_or(DecodeError::UnknownError(params[0])),
+            REPLY_READ if !params.is_empty() => Ok(Reply::Read(params.to_vec())),
+            REPLY_VERIFY => Checksum::from_bytes(params)
+                .map(Reply::Verify)
+                .ok_or(DecodeError::InvalidPayloadSize),
+            REPLY_SUCCESS | REPLY_ERROR | REPLY_READ => Err(DecodeError::InvalidPayloadSize),
+            _ => Err(DecodeError::UnknownCommand(kind)),
+        }
+    }
+}
+
+impl Command for Reply {
+    fn payload(&self) -> Vec<u8> {
+        match *self {
+            Reply::Success => vec![REPLY_SUCCESS],
+            Reply::Error(error) => vec![REPLY_ERROR, error.code()],
+            Reply::Read(ref page_data) => {
+                let mut payload = vec![REPLY_READ];
+                payload.extend(page_data.iter());
+                payload
+            }
+            Reply::Verify(checksum) => {
+                let mut payload = vec![REPLY_VERIFY];
+                payload.extend(checksum.to_bytes());
+                payload
+            }
+        }
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reply::Success => write!(f, "success"),
+            Reply::Error(error) => error.fmt(f),
+            Reply::Read(ref page_data) => write!(
+                f,
+                "page data ({} bytes, checksum 0x{:02x})",
+                page_data.len(),
+                command::checksum(page_data)
+            ),
+            Reply::Verify(checksum) => write!(f, "checksum {}", checksum),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::BootloaderError;
+    use protocol::{PROTOCOLS, PROTOCOL_V1};
+    use testing;
+
+    #[test]
+    fn round_trip() {
+        for protocol in PROTOCOLS.iter() {
+            for reply in [
+                Reply::Success,
+                Reply::Error(BootloaderError::InvalidChecksum),
+                Reply::Read(vec![0x00, 0x7f, 0x80, 0xff]),
+                Reply::Verify(protocol.integrity.checksum(&[0x42; 128])),
+            ] {
+                assert_eq!(Reply::from_sysex(&reply.to_sysex_with(protocol)), Ok(reply));
+            }
+        }
+    }
+
+    #[test]
+    fn firmware_encoding() {
+        assert_eq!(
+            Reply::from_sysex(&[0xf0, 0x00, 0x70, 0x01, 0x02, 0x00, 0x02, 0x00, 0xf7]),
+            Ok(Reply::Success)
+        );
+        assert_eq!(
+            Reply::from_sysex(&testing::frame(&PROTOCOL_V1, &[REPLY_ERROR, 8])),
+            Ok(Reply::Error(BootloaderError::InvalidPageNumber))
+        );
+        assert_eq!(
+            Reply::from_sysex(&testing::frame(&PROTOCOL_V1, &[REPLY_VERIFY, 0x5a])),
+            Ok(Reply::Verify(Checksum::Xor(0x5a)))
+        );
+    }
+
+    #[test]
+    fn invalid_replies() {
+        for &(payload, error) in &[
+            (&[REPLY_SUCCESS, 0][..], DecodeError::InvalidPayloadSize),
+            (&[REPLY_ERROR], DecodeError::InvalidPayloadSize),
+            (&[REPLY_ERROR, 9], DecodeError::UnknownError(9)),
+            (&[REPLY_READ], DecodeError::InvalidPayloadSize),
+            (&[REPLY_VERIFY], DecodeError::InvalidPayloadSize),
+            (&[REPLY_VERIFY, 1, 2, 3], DecodeError::InvalidPayloadSize),
+            (&[0x24], DecodeError::UnknownCommand(0x24)),
+        ] {
+            let message = testing::frame(&PROTOCOL_V1, payload);
+            assert_eq!(Reply::from_sysex(&message), Err(error));
+        }
+    }
+}
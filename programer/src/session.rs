@@ -0,0 +1,354 @@
+use std::time::{Duration, Instant};
+
+use command::{Command, Ping, Quit, Read, Verify, VerifyRange, Write, COMMAND_QUIT, COMMAND_WRITE};
+use error::{BootloaderError, Error, TransportError};
+use policy::{self, CommandPolicy, RetryPolicy};
+use protection::Protection;
+use protocol::{Checksum, Protocol, PROTOCOLS};
+use reply::Reply;
+use target::Target;
+use transport::Transport;
+
+// Runs single commands against a bootloader and checks their replies.
+pub struct Session<T: Transport> {
+    transport: T,
+    target: Target,
+    protection: Protection,
+    protocol: Protocol,
+    policy: RetryPolicy,
+    retries: usize,
+}
+
+impl<T: Transport> Session<T> {
+    pub fn new(transport: T, target: Target) -> Session<T> {
+        Session {
+            transport,
+            target,
+            protection: Protection::new(&target),
+            protocol: Protocol::default(),
+            policy: RetryPolicy::default(),
+            retries: 0,
+        }
+    }
+
+    pub fn with_protection(mut self, protection: Protection) -> Session<T> {
+        self.protection = protection;
+        self
+    }
+
+    // The protocol commands are encoded with. Replies are decoded in whatever
+    // version they arrive.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Session<T> {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: RetryPolicy) -> Session<T> {
+        self.policy = policy;
+        self
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    pub fn protection(&self) -> &Protection {
+        &self.protection
+    }
+
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    // Number of commands resent so far because of a transient error.
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    // Sends any command, refusing writes to protected pages before anything
+    // goes out. Quit is never resent here, whatever the policy says, see
+    // `quit`.
+    pub fn request<C, R, F>(
+        &mut self,
+        command: &C,
+        policy: CommandPolicy,
+        expect: F,
+    ) -> Result<R, Error>
+    where
+        C: Command,
+        F: Fn(Reply) -> Result<R, Error>,
+    {
+        let retries = match command.payload()[..] {
+            [COMMAND_WRITE, page_no, ..] => {
+                self.protection.check_page(page_no as usize)?;
+                policy.retries
+            }
+            [COMMAND_QUIT, ..] => 0,
+            _ => policy.retries,
+        };
+        let message = command.to_sysex_with(&self.protocol);
+        let mut attempt = 0;
+        loop {
+            let error = match self.exchange(&message, policy.timeout).and_then(&expect) {
+                Ok(result) => return Ok(result),
+                Err(error) => error,
+            };
+            if attempt == retries || !policy::is_transient(&error) {
+                return Err(error);
+            }
+            attempt += 1;
+            self.retries += 1;
+        }
+    }
+
+    pub fn ping(&mut self) -> Result<(), Error> {
+        let policy = self.policy.ping;
+        self.request(&Ping {}, policy, expect_success)
+    }
+
+    // Pings in every protocol version this host knows, newest first, and
+    // keeps the first one the bootloader accepts. Older bootloaders answer
+    // versions they do not know with a header mismatch, which steps down
+    // right away. On a noisy link that answer may be lost as well, so any
+    // other failure steps down once the ping's retries are used up.
+    pub fn negotiate(&mut self) -> Result<Protocol, Error> {
+        let policy = self.policy.ping;
+        let once = CommandPolicy {
+            retries: 0,
+            ..policy
+        };
+        let mut result = Ok(());
+        for protocol in PROTOCOLS.iter().rev() {
+            self.protocol = *protocol;
+            let mut attempt = 0;
+            result = loop {
+                let error = match self.request(&Ping {}, once, expect_success) {
+                    Ok(()) => break Ok(()),
+                    Err(error) => error,
+                };
+                let header_mismatch =
+                    matches!(error, Error::Bootloader(BootloaderError::HeaderMismatch));
+                if header_mismatch || attempt == policy.retries || !policy::is_transient(&error) {
+                    break Err(error);
+                }
+                attempt += 1;
+                self.retries += 1;
+            };
+            if result.is_ok() {
+                break;
+            }
+        }
+        result.map(|()| self.protocol)
+    }
+
+    pub fn write(&mut self, write: &Write) -> Result<(), Error> {
+        let policy = self.policy.write;
+        self.request(write, policy, expect_success)
+    }
+
+    pub fn read(&mut self, page_no: usize) -> Result<Vec<u8>, Error> {
+        let read = Read::new(&self.target, page_no)?;
+        let page_size = self.target.page_size;
+        let policy = self.policy.read;
+        self.request(&read, policy, |reply| match reply {
+            Reply::Read(page_data) if page_data.len() == page_size => Ok(page_data),
+            reply => Err(Error::UnexpectedReply(reply)),
+        })
+    }
+
+    pub fn verify(&mut self, page_no: usize) -> Result<Checksum, Error> {
+        let verify = Verify::new(&self.target, page_no)?;
+        let policy = self.policy.verify;
+        self.request(&verify, policy, |reply| match reply {
+            Reply::Verify(checksum) => Ok(checksum),
+            reply => Err(Error::UnexpectedReply(reply)),
+        })
+    }
+
+    // One checksum over pages first_page to last_page inclusive. Only
+    // bootloaders whose protocol has `verify_range` answer it.
+    pub fn verify_range(&mut self, first_page: usize, last_page: usize) -> Result<Checksum, Error> {
+        let verify_range = VerifyRange::new(&self.target, first_page, last_page)?;
+        let policy = self.policy.verify;
+        self.request(&verify_range, policy, |reply| match reply {
+            Reply::Verify(checksum) => Ok(checksum),
+            reply => Err(Error::UnexpectedReply(reply)),
+        })
+    }
+
+    // The bootloader replies before it jumps to the application, so a lost
+    // reply usually means Quit did land. It is only sent again if the
+    // bootloader still answers a ping.
+    pub fn quit(&mut self) -> Result<(), Error> {
+        let policy = self.policy.quit;
+        let mut attempt = 0;
+        loop {
+            let error = match self.request(&Quit {}, policy, expect_success) {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            if attempt == policy.retries || !policy::is_transient(&error) {
+                return Err(error);
+            }
+            match self.ping() {
+                Ok(()) => {}
+                Err(Error::Transport(TransportError::Timeout)) => return Ok(()),
+                Err(error) => return Err(error),
+            }
+            attempt += 1;
+            self.retries += 1;
+        }
+    }
+
+    fn exchange(&mut self, message: &[u8], timeout: Duration) -> Result<Reply, Error> {
+        self.discard_pending();
+        self.transport.send(message)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let frame = match self.transport.receive(timeout) {
+                // Only an earlier frame can be cut short by the one after it.
+                Err(TransportError::Truncated(_)) => continue,
+                frame => frame?,
+            };
+            match Reply::from_sysex(&frame)? {
+                // Sent when our 0xf0 interrupted an earlier, truncated
+                // message. The reply to this message is still to come.
+                Reply::Error(BootloaderError::IncompleteMessage) => continue,
+                Reply::Error(error) => return Err(Error::Bootloader(error)),
+                reply => return Ok(reply),
+            }
+        }
+    }
+
+    // Late or duplicated replies to an earlier attempt must not be taken as
+    // the answer to the next command.
+    fn discard_pending(&mut self) {
+        loop {
+            match self.transport.receive(Duration::from_millis(0)) {
+                Ok(_) | Err(TransportError::Truncated(_)) => {}
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+fn expect_success(reply: Reply) -> Result<(), Error> {
+    match reply {
+        Reply::Success => Ok(()),
+        reply => Err(Error::UnexpectedReply(reply)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::Request;
+    use error::CommandError;
+    use image::ERASED;
+    use protocol::{PROTOCOL_V1, PROTOCOL_V3};
+    use target::ATMEGA16;
+    use transport::faults::{FaultInjector, Faults};
+    use transport::simulator::Simulator;
+
+    // Counts the messages sent through it.
+    struct Counter<T: Transport> {
+        inner: T,
+        sent: usize,
+    }
+
+    impl<T: Transport> Transport for Counter<T> {
+        fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+            self.sent += 1;
+            self.inner.send(message)
+        }
+
+        fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+            self.inner.receive(timeout)
+        }
+    }
+
+    #[test]
+    fn negotiate_steps_down_on_header_mismatch() {
+        for &(ref protocols, expected) in &[
+            (vec![PROTOCOL_V1], PROTOCOL_V1),
+            (vec![PROTOCOL_V1, PROTOCOL_V3], PROTOCOL_V3),
+            (PROTOCOLS.to_vec(), PROTOCOLS[PROTOCOLS.len() - 1]),
+        ] {
+            let mut counter = Counter {
+                inner: Simulator::new(ATMEGA16).with_protocols(protocols),
+                sent: 0,
+            };
+            let mut session = Session::new(&mut counter, ATMEGA16);
+            assert_eq!(session.negotiate().unwrap(), expected);
+            assert_eq!(session.retries(), 0);
+            let skipped = PROTOCOLS
+                .iter()
+                .filter(|protocol| protocol.version > expected.version)
+                .count();
+            assert_eq!(counter.sent, skipped + 1);
+        }
+    }
+
+    #[test]
+    fn request_refuses_protected_write() {
+        let mut simulator = Simulator::new(ATMEGA16);
+        let boot_page = ATMEGA16.boot_page();
+        let write = Write::new(&ATMEGA16, boot_page, vec![0x42; ATMEGA16.page_size]).unwrap();
+        let is_refused = |result: Result<Reply, Error>| match result {
+            Err(Error::Command(error)) => {
+                error
+                    == CommandError::ProtectedPage {
+                        page_no: boot_page,
+                        first_protected: boot_page,
+                    }
+            }
+            _ => false,
+        };
+        {
+            let mut session = Session::new(&mut simulator, ATMEGA16);
+            let policy = session.policy().write;
+            assert!(is_refused(session.request(&write, policy, Ok)));
+            let request = Request::Write(write.clone());
+            assert!(is_refused(session.request(&request, policy, Ok)));
+        }
+        assert!(simulator.flash()[ATMEGA16.boot_start..]
+            .iter()
+            .all(|&byte| byte == ERASED));
+    }
+
+    #[test]
+    fn request_never_resends_quit() {
+        let deaf = FaultInjector::new(Simulator::new(ATMEGA16), 1).with_incoming(Faults {
+            drop_byte: 1.0,
+            ..Faults::none()
+        });
+        let mut counter = Counter {
+            inner: deaf,
+            sent: 0,
+        };
+        let request = Request::Quit(Quit {});
+        {
+            let mut session = Session::new(&mut counter, ATMEGA16);
+            let policy = CommandPolicy::new(10, 2);
+            assert!(matches!(
+                session.request(&request, policy, expect_success),
+                Err(Error::Transport(TransportError::Timeout))
+            ));
+            assert_eq!(session.retries(), 0);
+        }
+        assert_eq!(counter.sent, 1);
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use command::{Command, Ping, Quit};
+use error::CommandError;
+use image::Image;
+use protection::Protection;
+use protocol::Protocol;
+use reply::Reply;
+use target::Target;
+
+// 31250 baud with a start and a stop bit around every byte.
+pub const BYTES_PER_SECOND: u64 = 3125;
+
+// Erasing and programming a page take up to 4.5 ms each, and the bootloader
+// does not read MIDI while it waits for the SPM unit.
+const PAGE_PROGRAMMING_MS: u64 = 9;
+
+// Headroom for senders with coarse timers or busy USB interfaces.
+const MARGIN_MS: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub description: String,
+    pub bytes: Vec<u8>,
+    // How long a sender should wait after this message before the next one.
+    pub delay: Duration,
+}
+
+// Renders a whole flashing job for generic sysex librarians, which send
+// messages blindly with a fixed delay instead of waiting for replies. Blank
+// pages are written as well, since nobody checks that they are erased.
+pub fn export(
+    image: &Image,
+    target: &Target,
+    protection: &Protection,
+    protocol: &Protocol,
+) -> Result<Vec<Message>, CommandError> {
+    let writes = image.to_writes(target, protection, false)?;
+
+    let ping = message("ping".to_string(), &Ping {}, protocol, 0);
+    let mut messages = vec![ping];
+    for write in writes {
+        let description = format!("write page {}", write.page_no());
+        messages.push(message(description, &write, protocol, PAGE_PROGRAMMING_MS));
+    }
+    messages.push(message("quit".to_string(), &Quit {}, protocol, 0));
+    Ok(messages)
+}
+
+// The concatenated frames, which is all a .syx file is.
+pub fn to_bytes(messages: &[Message]) -> Vec<u8> {
+    messages
+        .iter()
+        .flat_map(|message| message.bytes.iter().cloned())
+        .collect()
+}
+
+pub fn duration(messages: &[Message]) -> Duration {
+    messages
+        .iter()
+        .map(|message| transmission_time(message.bytes.len()) + message.delay)
+        .sum()
+}
+
+// A tab separated sidecar listing every message of the .syx file with its
+// offset and the delay to keep after it.
+pub fn manifest(messages: &[Message], syx_name: &str, target: &Target) -> String {
+    let mut manifest = format!(
+        "# {}: {} messages for {}, about {:.1} s at 31250 baud\n\
+         # send the messages in order and wait delay_ms after each one\n\
+         # message\toffset\tlength\tdelay_ms\tcommand\n",
+        syx_name,
+        messages.len(),
+        target.name,
+        duration(messages).as_secs_f64()
+    );
+    let mut offset = 0;
+    for (index, message) in messages.iter().enumerate() {
+        manifest.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            index + 1,
+            offset,
+            message.bytes.len(),
+            message.delay.as_millis(),
+            message.description
+        ));
+        offset += message.bytes.len();
+    }
+    manifest
+}
+
+fn message<C: Command>(
+    description: String,
+    command: &C,
+    protocol: &Protocol,
+    processing_ms: u64,
+) -> Message {
+    // Every command used here is answered with a success reply, which the
+    // bootloader sends before it listens again.
+    let reply = Reply::Success.to_sysex_with(protocol).len();
+    Message {
+        description,
+        bytes: command.to_sysex_with(protocol),
+        delay: Duration::from_millis(processing_ms + MARGIN_MS) + transmission_time(reply),
+    }
+}
+
+// Rounded up to whole milliseconds, which is what librarians let you enter.
+fn transmission_time(bytes: usize) -> Duration {
+    Duration::from_millis((bytes as u64 * 1000).div_ceil(BYTES_PER_SECOND))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ERASED;
+    use protocol::PROTOCOL_V1;
+    use target::ATMEGA16;
+
+    // Pages 0 to 2 with page 1 blank.
+    fn export_v1() -> Vec<Message> {
+        let mut image = Image::new();
+        for address in 0..3 * ATMEGA16.page_size {
+            image.set(address, address as u8 & 0x7f);
+        }
+        for address in ATMEGA16.page_size..2 * ATMEGA16.page_size {
+            image.set(address, ERASED);
+        }
+        export(&image, &ATMEGA16, &Protection::new(&ATMEGA16), &PROTOCOL_V1).unwrap()
+    }
+
+    #[test]
+    fn export_writes_every_page_and_page_0_last() {
+        let messages = export_v1();
+        let descriptions: Vec<&str> = messages
+            .iter()
+            .map(|message| message.description.as_str())
+            .collect();
+        assert_eq!(
+            descriptions,
+            vec![
+                "ping",
+                "write page 1",
+                "write page 2",
+                "write page 0",
+                "quit"
+            ]
+        );
+    }
+
+    #[test]
+    fn delays_cover_processing_and_the_reply() {
+        assert_eq!(transmission_time(0), Duration::from_millis(0));
+        assert_eq!(transmission_time(1), Duration::from_millis(1));
+        assert_eq!(transmission_time(25), Duration::from_millis(8));
+        assert_eq!(transmission_time(26), Duration::from_millis(9));
+
+        let messages = export_v1();
+        let delays: Vec<u128> = messages
+            .iter()
+            .map(|message| message.delay.as_millis())
+            .collect();
+        // 10 ms margin and 3 ms for the 9 byte reply, plus 9 ms programming
+        // after every write.
+        assert_eq!(delays, vec![13, 22, 22, 22, 13]);
+        // 3 ms for ping and quit and 86 ms for every 267 byte write.
+        assert_eq!(duration(&messages), Duration::from_millis(356));
+    }
+
+    #[test]
+    fn manifest_lists_offsets_and_delays() {
+        let messages = export_v1();
+        let manifest = manifest(&messages, "app.syx", &ATMEGA16);
+        let mut lines = manifest.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("# app.syx: 5 messages for atmega16, about "));
+        assert_eq!(lines.next().map(|line| line.starts_with('#')), Some(true));
+        assert_eq!(lines.next().map(|line| line.starts_with('#')), Some(true));
+        let rows: Vec<&str> = lines.collect();
+        assert_eq!(
+            rows,
+            vec![
+                "1\t0\t9\t13\tping",
+                "2\t9\t267\t22\twrite page 1",
+                "3\t276\t267\t22\twrite page 2",
+                "4\t543\t267\t22\twrite page 0",
+                "5\t810\t9\t13\tquit",
+            ]
+        );
+        assert_eq!(to_bytes(&messages).len(), 819);
+    }
+}
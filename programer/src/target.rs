@@ -0,0 +1,126 @@
+// Describes the AVR part the bootloader runs on. The boot section start is
+// where firmware/runfile links the bootloader, i.e. the last 1 KiB of flash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    pub name: &'static str,
+    pub signature: [u8; 3],
+    pub flash_size: usize,
+    pub page_size: usize,
+    pub boot_start: usize,
+    pub min_boot_size: usize,
+    pub eeprom_size: usize,
+}
+
+pub const ATMEGA16: Target = Target {
+    name: "atmega16",
+    signature: [0x1e, 0x94, 0x03],
+    flash_size: 0x4000,
+    page_size: 128,
+    boot_start: 0x3c00,
+    min_boot_size: 256,
+    eeprom_size: 512,
+};
+
+pub const ATMEGA32: Target = Target {
+    name: "atmega32",
+    signature: [0x1e, 0x95, 0x02],
+    flash_size: 0x8000,
+    page_size: 128,
+    boot_start: 0x7c00,
+    min_boot_size: 512,
+    eeprom_size: 1024,
+};
+
+pub const ATMEGA164P: Target = Target {
+    name: "atmega164p",
+    signature: [0x1e, 0x94, 0x0a],
+    flash_size: 0x4000,
+    page_size: 128,
+    boot_start: 0x3c00,
+    min_boot_size: 256,
+    eeprom_size: 512,
+};
+
+pub const ATMEGA324P: Target = Target {
+    name: "atmega324p",
+    signature: [0x1e, 0x95, 0x08],
+    flash_size: 0x8000,
+    page_size: 128,
+    boot_start: 0x7c00,
+    min_boot_size: 512,
+    eeprom_size: 1024,
+};
+
+pub const ATMEGA644P: Target = Target {
+    name: "atmega644p",
+    signature: [0x1e, 0x96, 0x0a],
+    flash_size: 0x10000,
+    page_size: 256,
+    boot_start: 0xfc00,
+    min_boot_size: 1024,
+    eeprom_size: 2048,
+};
+
+pub static TARGETS: [Target; 5] = [ATMEGA16, ATMEGA32, ATMEGA164P, ATMEGA324P, ATMEGA644P];
+
+impl Target {
+    pub fn by_name(name: &str) -> Option<&'static Target> {
+        TARGETS
+            .iter()
+            .find(|target| target.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn by_signature(signature: [u8; 3]) -> Option<&'static Target> {
+        TARGETS.iter().find(|target| target.signature == signature)
+    }
+
+    pub fn num_pages(&self) -> usize {
+        self.flash_size / self.page_size
+    }
+
+    pub fn boot_page(&self) -> usize {
+        self.boot_start / self.page_size
+    }
+
+    pub fn page_of(&self, address: usize) -> usize {
+        address / self.page_size
+    }
+
+    // Boot section size in bytes selected by the BOOTSZ1:0 bits of the high fuse.
+    pub fn boot_size(&self, high_fuse: u8) -> usize {
+        self.min_boot_size << (3 - ((high_fuse >> 1) & 0x03))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometry() {
+        assert_eq!(ATMEGA16.num_pages(), 128);
+        assert_eq!(ATMEGA16.boot_page(), 120);
+        assert_eq!(ATMEGA16.page_of(0x3bff), 119);
+        assert_eq!(ATMEGA644P.num_pages(), 256);
+        assert_eq!(ATMEGA644P.boot_page(), 252);
+    }
+
+    #[test]
+    fn boot_size_follows_bootsz_fuses() {
+        assert_eq!(ATMEGA16.boot_size(0xff), 256);
+        assert_eq!(ATMEGA16.boot_size(0xfd), 512);
+        assert_eq!(ATMEGA16.boot_size(0xfb), 1024);
+        assert_eq!(ATMEGA16.boot_size(0xf9), 2048);
+        // The factory default, BOOTSZ1:0 = 00.
+        assert_eq!(ATMEGA16.boot_size(0x99), 2048);
+        assert_eq!(ATMEGA644P.boot_size(0xff), 1024);
+        assert_eq!(ATMEGA644P.boot_size(0xf9), 8192);
+    }
+
+    #[test]
+    fn lookup() {
+        assert_eq!(Target::by_name("ATmega32"), Some(&ATMEGA32));
+        assert_eq!(Target::by_name("atmega8"), None);
+        assert_eq!(Target::by_signature([0x1e, 0x96, 0x0a]), Some(&ATMEGA644P));
+    }
+}
@@ -0,0 +1,26 @@
+// Fixtures shared by the unit tests.
+
+use command::FOOTER;
+use image::Image;
+use protocol::Protocol;
+
+// A message around an arbitrary payload with the protocol's checksum
+// appended, for commands and replies the typed encoders refuse to build.
+pub fn frame(protocol: &Protocol, payload: &[u8]) -> Vec<u8> {
+    let mut payload = payload.to_vec();
+    payload.extend(protocol.integrity.checksum(&payload).to_bytes());
+    let mut message = protocol.header().to_vec();
+    message.extend(protocol.encoding.encode(&payload));
+    message.extend(FOOTER.to_vec());
+    message
+}
+
+// The first `size` bytes of flash filled with a pattern that differs from
+// page to page.
+pub fn test_image(size: usize) -> Image {
+    let mut image = Image::new();
+    for address in 0..size {
+        image.set(address, (address * 7 + address / 300) as u8);
+    }
+    image
+}
@@ -0,0 +1,201 @@
+use std::fmt;
+
+use command::Request;
+use error::{DecodeError, Error};
+use framer::{Event, Framer};
+use reply::Reply;
+use session::Session;
+use target::Target;
+use transport::Transport;
+
+// One frame of a .syx file or raw MIDI capture, decoded as far as possible.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Request(Request),
+    Reply(Reply),
+    Truncated(Vec<u8>),
+    Invalid(Vec<u8>, DecodeError),
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Entry::Request(ref request) => write!(f, "> {}", request),
+            Entry::Reply(ref reply) => write!(f, "< {}", reply),
+            Entry::Truncated(ref frame) => {
+                write!(f, "! frame cut short after {} bytes", frame.len())
+            }
+            Entry::Invalid(ref frame, error) => {
+                write!(f, "! invalid {} byte frame: {}", frame.len(), error)
+            }
+        }
+    }
+}
+
+// Splits a capture into frames addressed to us and decodes each one as a
+// command or, failing that, as a reply. Anything else in the capture, such
+// as notes or other manufacturers' sysex, is skipped.
+pub fn decode(capture: &[u8], target: &Target) -> Vec<Entry> {
+    Framer::new()
+        .push(capture)
+        .into_iter()
+        .map(|event| match event {
+            Event::Frame(frame) => decode_frame(frame, target),
+            Event::Truncated(frame) => Entry::Truncated(frame),
+        })
+        .collect()
+}
+
+fn decode_frame(frame: Vec<u8>, target: &Target) -> Entry {
+    match Request::from_sysex(&frame, target) {
+        Ok(request) => Entry::Request(request),
+        Err(DecodeError::UnknownCommand(_)) => match Reply::from_sysex(&frame) {
+            Ok(reply) => Entry::Reply(reply),
+            Err(error) => Entry::Invalid(frame, error),
+        },
+        Err(error) => Entry::Invalid(frame, error),
+    }
+}
+
+#[derive(Debug)]
+pub struct Step {
+    pub request: Request,
+    // The reply recorded after the request, if the capture has one.
+    pub captured: Option<Reply>,
+    pub result: Result<Reply, Error>,
+}
+
+impl Step {
+    // Whether the device answered as it did in the capture, or without an
+    // error if the capture holds no replies.
+    pub fn is_match(&self) -> bool {
+        match (self.captured.as_ref(), self.result.as_ref()) {
+            (Some(captured), Ok(reply)) => captured == reply,
+            (Some(Reply::Error(captured)), Err(Error::Bootloader(error))) => captured == error,
+            (None, Ok(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "> {}\n< ", self.request)?;
+        match self.result {
+            Ok(ref reply) => write!(f, "{}", reply)?,
+            Err(ref error) => write!(f, "{}", error)?,
+        }
+        match self.captured {
+            Some(ref captured) if !self.is_match() => write!(f, " (captured: {})", captured),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Sends every command of a capture again, one at a time and in order, and
+// pairs each answer with the reply recorded after that command. Writes to
+// protected pages are refused instead of sent.
+pub fn replay<T: Transport>(session: &mut Session<T>, entries: &[Entry]) -> Vec<Step> {
+    let mut steps = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let request = match *entry {
+            Entry::Request(ref request) => request,
+            _ => continue,
+        };
+        let captured = entries[index + 1..]
+            .iter()
+            .take_while(|entry| !matches!(entry, Entry::Request(_)))
+            .filter_map(|entry| match *entry {
+                Entry::Reply(ref reply) => Some(reply.clone()),
+                _ => None,
+            })
+            .next();
+
+        let policy = session.policy().for_request(request);
+        let result = session.request(request, policy, Ok);
+        steps.push(Step {
+            request: request.clone(),
+            captured,
+            result,
+        });
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Command, Ping, Quit, Verify, Write};
+    use protocol::{Checksum, PROTOCOL_V1};
+    use target::ATMEGA16;
+    use testing;
+    use transport::simulator::Simulator;
+
+    #[test]
+    fn decode_sorts_frames() {
+        let ping = Request::Ping(Ping {});
+        let mut bad_checksum = testing::frame(&PROTOCOL_V1, &[0x10]);
+        bad_checksum[5] ^= 0x01;
+        let mut capture = ping.to_sysex();
+        capture.extend(&[0x90, 0x3c, 0x40]);
+        capture.extend(Reply::Success.to_sysex());
+        capture.extend(&[0xf0, 0x41, 0x10, 0x42, 0xf7]);
+        capture.extend(&bad_checksum);
+        capture.extend(&[0xf0, 0x00, 0x70, 0x01, 0x01]);
+        capture.extend(Request::Quit(Quit {}).to_sysex());
+
+        assert_eq!(
+            decode(&capture, &ATMEGA16),
+            vec![
+                Entry::Request(ping),
+                Entry::Reply(Reply::Success),
+                Entry::Invalid(bad_checksum, DecodeError::InvalidChecksum),
+                Entry::Truncated(vec![0xf0, 0x00, 0x70, 0x01, 0x01]),
+                Entry::Request(Request::Quit(Quit {})),
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_compares_with_captured_replies() {
+        let page_data = vec![0x42; ATMEGA16.page_size];
+        let write =
+            |page_no| Request::Write(Write::new(&ATMEGA16, page_no, page_data.clone()).unwrap());
+        let verify = Request::Verify(Verify::new(&ATMEGA16, 1).unwrap());
+        let entries = vec![
+            Entry::Request(Request::Ping(Ping {})),
+            Entry::Reply(Reply::Success),
+            Entry::Request(write(1)),
+            Entry::Truncated(vec![0xf0]),
+            Entry::Reply(Reply::Success),
+            Entry::Request(verify.clone()),
+            Entry::Reply(Reply::Verify(Checksum::Xor(0x11))),
+            Entry::Request(write(ATMEGA16.boot_page())),
+            Entry::Reply(Reply::Success),
+            Entry::Request(Request::Quit(Quit {})),
+        ];
+
+        let mut simulator = Simulator::new(ATMEGA16);
+        let steps = replay(&mut Session::new(&mut simulator, ATMEGA16), &entries);
+        let requests: Vec<&Request> = steps.iter().map(|step| &step.request).collect();
+        assert_eq!(
+            requests,
+            vec![
+                &Request::Ping(Ping {}),
+                &write(1),
+                &verify,
+                &write(ATMEGA16.boot_page()),
+                &Request::Quit(Quit {}),
+            ]
+        );
+        let matches: Vec<bool> = steps.iter().map(Step::is_match).collect();
+        assert_eq!(matches, vec![true, true, false, false, true]);
+        assert_eq!(
+            steps[2].result.as_ref().ok(),
+            Some(&Reply::Verify(PROTOCOL_V1.integrity.checksum(&page_data)))
+        );
+        assert!(matches!(steps[3].result, Err(Error::Command(_))));
+        assert_eq!(steps[4].captured, None);
+        assert!(simulator.is_running_application());
+    }
+}
@@ -0,0 +1,121 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use alsa;
+use alsa::rawmidi::Rawmidi;
+use alsa::{Direction, PollDescriptors};
+
+use discovery;
+use error::TransportError;
+use transport::{FrameQueue, Transport};
+
+const BUFFER_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlsaPort {
+    pub id: String,
+    pub name: String,
+    pub input: bool,
+    pub output: bool,
+}
+
+// Talks to an ALSA rawmidi device such as "hw:1,0,0" without going through
+// portmidi. The snd-virmidi module provides loopback devices for testing.
+pub struct AlsaTransport {
+    input: Rawmidi,
+    output: Rawmidi,
+    frames: FrameQueue,
+}
+
+impl AlsaTransport {
+    pub fn open(id: &str) -> Result<AlsaTransport, TransportError> {
+        let input = Rawmidi::new(id, Direction::Capture, true).map_err(backend_error)?;
+        let output = Rawmidi::new(id, Direction::Playback, false).map_err(backend_error)?;
+        Ok(AlsaTransport {
+            input,
+            output,
+            frames: FrameQueue::new(),
+        })
+    }
+
+    pub fn ports() -> Result<Vec<AlsaPort>, TransportError> {
+        let mut ports: Vec<AlsaPort> = Vec::new();
+        for card in alsa::card::Iter::new() {
+            let card = card.map_err(backend_error)?;
+            let ctl = alsa::Ctl::from_card(&card, false).map_err(backend_error)?;
+            for info in alsa::rawmidi::Iter::new(&ctl) {
+                let info = info.map_err(backend_error)?;
+                let id = format!(
+                    "hw:{},{},{}",
+                    card.get_index(),
+                    info.get_device(),
+                    info.get_subdevice()
+                );
+                let index = match ports.iter().position(|port| port.id == id) {
+                    Some(index) => index,
+                    None => {
+                        ports.push(AlsaPort {
+                            id,
+                            name: info.get_subdevice_name().unwrap_or_default(),
+                            input: false,
+                            output: false,
+                        });
+                        ports.len() - 1
+                    }
+                };
+                match info.get_stream() {
+                    Direction::Capture => ports[index].input = true,
+                    Direction::Playback => ports[index].output = true,
+                }
+            }
+        }
+        Ok(ports)
+    }
+
+    // Pings every duplex port and returns those with a bootloader listening.
+    pub fn discover(timeout: Duration) -> Result<Vec<AlsaPort>, TransportError> {
+        let ports = AlsaTransport::ports()?
+            .into_iter()
+            .filter(|port| port.input && port.output)
+            .collect();
+        Ok(discovery::discover(ports, timeout, |port| {
+            AlsaTransport::open(&port.id)
+        }))
+    }
+}
+
+impl Transport for AlsaTransport {
+    fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        self.output.io().write_all(message)?;
+        self.output.drain().map_err(backend_error)
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        let input = &self.input;
+        let mut buffer = [0; BUFFER_SIZE];
+        self.frames.receive(timeout, |frames, remaining| {
+            if !wait_readable(input, remaining)? {
+                return Ok(false);
+            }
+            match input.io().read(&mut buffer) {
+                Ok(count) => {
+                    frames.push(&buffer[..count]);
+                    Ok(count > 0)
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                Err(error) => Err(TransportError::Io(error)),
+            }
+        })
+    }
+}
+
+fn wait_readable(input: &Rawmidi, timeout: Duration) -> Result<bool, TransportError> {
+    let mut fds = input.get().map_err(backend_error)?;
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let ready = alsa::poll::poll(&mut fds, timeout_ms).map_err(backend_error)?;
+    Ok(ready > 0)
+}
+
+fn backend_error(error: alsa::Error) -> TransportError {
+    TransportError::Backend(error.to_string())
+}
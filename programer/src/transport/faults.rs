@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+use error::TransportError;
+use transport::{FrameQueue, Transport};
+
+// Byte-level damage applied to every frame passing in one direction. All
+// values are probabilities between 0.0 and 1.0.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Faults {
+    pub drop_byte: f64,
+    pub corrupt_nibble: f64,
+    pub truncate_frame: f64,
+}
+
+impl Faults {
+    pub fn none() -> Faults {
+        Faults::default()
+    }
+}
+
+// Wraps a transport and mangles traffic the way a glitching MIDI DIN link
+// would, driven by a seeded RNG so that every failure is reproducible.
+pub struct FaultInjector<T: Transport> {
+    inner: T,
+    rng: Rng,
+    outgoing: Faults,
+    incoming: Faults,
+    duplicate_reply: f64,
+    latency: Duration,
+    frames: FrameQueue,
+    duplicates: VecDeque<Vec<u8>>,
+}
+
+impl<T: Transport> FaultInjector<T> {
+    pub fn new(inner: T, seed: u64) -> FaultInjector<T> {
+        FaultInjector {
+            inner,
+            rng: Rng::new(seed),
+            outgoing: Faults::none(),
+            incoming: Faults::none(),
+            duplicate_reply: 0.0,
+            latency: Duration::from_millis(0),
+            frames: FrameQueue::new(),
+            duplicates: VecDeque::new(),
+        }
+    }
+
+    pub fn with_outgoing(mut self, faults: Faults) -> FaultInjector<T> {
+        self.outgoing = faults;
+        self
+    }
+
+    pub fn with_incoming(mut self, faults: Faults) -> FaultInjector<T> {
+        self.incoming = faults;
+        self
+    }
+
+    pub fn with_duplicate_replies(mut self, probability: f64) -> FaultInjector<T> {
+        self.duplicate_reply = probability;
+        self
+    }
+
+    // Replies slower than the receive timeout stay queued and show up late.
+    pub fn with_latency(mut self, latency: Duration) -> FaultInjector<T> {
+        self.latency = latency;
+        self
+    }
+
+    pub fn inner(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn mangle(&mut self, frame: &[u8], faults: Faults) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(frame.len());
+        for &byte in frame {
+            if self.rng.chance(faults.drop_byte) {
+                continue;
+            }
+            if byte < 0x80 && self.rng.chance(faults.corrupt_nibble) {
+                bytes.push(byte ^ (0x10 << self.rng.below(3)));
+            } else {
+                bytes.push(byte);
+            }
+        }
+        if bytes.len() > 1 && self.rng.chance(faults.truncate_frame) {
+            let len = 1 + self.rng.below(bytes.len() - 1);
+            bytes.truncate(len);
+        }
+        bytes
+    }
+}
+
+impl<T: Transport> Transport for FaultInjector<T> {
+    fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        let message = self.mangle(message, self.outgoing);
+        self.inner.send(&message)
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        if let Some(frame) = self.duplicates.pop_front() {
+            return Ok(frame);
+        }
+        if self.latency > timeout {
+            thread::sleep(timeout);
+            return Err(TransportError::Timeout);
+        }
+        thread::sleep(self.latency);
+
+        loop {
+            if let Some(frame) = self.frames.pop() {
+                let frame = frame?;
+                if self.rng.chance(self.duplicate_reply) {
+                    self.duplicates.push_back(frame.clone());
+                }
+                return Ok(frame);
+            }
+            let frame = self.inner.receive(timeout - self.latency)?;
+            let frame = self.mangle(&frame, self.incoming);
+            self.frames.push(&frame);
+        }
+    }
+}
+
+// xorshift64*, good enough to pick faults and free of dependencies.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    // Seeds are scrambled with splitmix64 so that neighbouring seeds give
+    // unrelated sequences and zero is never used as state.
+    fn new(seed: u64) -> Rng {
+        let mut state = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        state ^= state >> 31;
+        Rng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use programmer::Programmer;
+    use session::Session;
+    use target::ATMEGA16;
+    use testing;
+    use transport::simulator::Simulator;
+
+    const SEEDS: u64 = 8;
+
+    // Flashes the test image through the injector and checks that the host
+    // recovered, returning how many commands had to be sent again.
+    fn flash_and_check(injector: FaultInjector<Simulator>) -> usize {
+        let image = testing::test_image(12 * ATMEGA16.page_size);
+        let mut session = Session::new(injector, ATMEGA16);
+        let report = {
+            let mut programmer = Programmer::new(session);
+            let report = programmer.flash(&image).unwrap();
+            session = programmer.into_session();
+            report
+        };
+        assert!(report.is_success(), "{:?}", report.failed);
+        let retries = session.retries() + report.retried.len();
+        let simulator = session.into_transport().into_inner();
+        for (address, byte) in image.iter() {
+            assert_eq!(simulator.flash()[address], byte, "address {}", address);
+        }
+        assert!(simulator.is_running_application());
+        retries
+    }
+
+    fn recovers_from(faults: Faults) {
+        let mut retries = 0;
+        for seed in 0..SEEDS {
+            let injector = FaultInjector::new(Simulator::new(ATMEGA16), seed)
+                .with_outgoing(faults)
+                .with_incoming(faults);
+            retries += flash_and_check(injector);
+        }
+        assert!(retries > 0, "no fault was injected");
+    }
+
+    #[test]
+    fn recovers_from_dropped_bytes() {
+        recovers_from(Faults {
+            drop_byte: 0.001,
+            ..Faults::none()
+        });
+    }
+
+    #[test]
+    fn recovers_from_corrupted_nibbles() {
+        recovers_from(Faults {
+            corrupt_nibble: 0.001,
+            ..Faults::none()
+        });
+    }
+
+    #[test]
+    fn recovers_from_truncated_frames() {
+        recovers_from(Faults {
+            truncate_frame: 0.05,
+            ..Faults::none()
+        });
+    }
+
+    #[test]
+    fn recovers_from_duplicate_replies() {
+        for seed in 0..SEEDS {
+            let injector =
+                FaultInjector::new(Simulator::new(ATMEGA16), seed).with_duplicate_replies(0.2);
+            flash_and_check(injector);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let faults = Faults {
+            drop_byte: 0.01,
+            corrupt_nibble: 0.01,
+            truncate_frame: 0.1,
+        };
+        let mangled = |seed| {
+            let mut injector = FaultInjector::new(Simulator::new(ATMEGA16), seed);
+            (0..20)
+                .map(|_| injector.mangle(&[0x05; 64], faults))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(mangled(7), mangled(7));
+        assert_ne!(mangled(7), mangled(8));
+    }
+}
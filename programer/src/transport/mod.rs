@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use error::TransportError;
+use framer::{Event, Framer};
+
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+pub mod alsa;
+
+pub mod faults;
+
+#[cfg(feature = "portmidi")]
+pub mod portmidi;
+
+#[cfg(target_os = "linux")]
+pub mod serial;
+
+pub mod simulator;
+
+pub trait Transport {
+    fn send(&mut self, message: &[u8]) -> Result<(), TransportError>;
+
+    // Returns the next complete sysex frame addressed to us.
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError>;
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        (**self).send(message)
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        (**self).receive(timeout)
+    }
+}
+
+// Buffers frames for transports that read a raw byte stream, which may
+// complete several frames in one read.
+pub struct FrameQueue {
+    framer: Framer,
+    events: VecDeque<Event>,
+}
+
+impl FrameQueue {
+    pub fn new() -> FrameQueue {
+        FrameQueue {
+            framer: Framer::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.events.extend(self.framer.push(bytes));
+    }
+
+    pub fn pop(&mut self) -> Option<Result<Vec<u8>, TransportError>> {
+        self.events.pop_front().map(|event| match event {
+            Event::Frame(frame) => Ok(frame),
+            Event::Truncated(frame) => Err(TransportError::Truncated(frame)),
+        })
+    }
+
+    // Waits for the next frame. `read` waits at most the given time for input,
+    // pushes whatever arrived and tells whether there was any. Even without
+    // time left whatever has already arrived is read, so that a zero timeout
+    // drains the input.
+    pub fn receive<F>(&mut self, timeout: Duration, mut read: F) -> Result<Vec<u8>, TransportError>
+    where
+        F: FnMut(&mut FrameQueue, Duration) -> Result<bool, TransportError>,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(frame) = self.pop() {
+                return frame;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !read(self, remaining)? && Instant::now() >= deadline {
+                return Err(TransportError::Timeout);
+            }
+        }
+    }
+}
+
+impl Default for FrameQueue {
+    fn default() -> FrameQueue {
+        FrameQueue::new()
+    }
+}
@@ -0,0 +1,111 @@
+use std::thread;
+use std::time::Duration;
+
+use pm;
+
+use command::FOOTER;
+use discovery;
+use error::TransportError;
+use transport::{FrameQueue, Transport};
+
+const BUFFER_SIZE: usize = 1024;
+const POLL_INTERVAL_MS: u64 = 1;
+
+pub struct PortMidiTransport<'a> {
+    input: pm::InputPort<'a>,
+    output: pm::OutputPort<'a>,
+    frames: FrameQueue,
+}
+
+impl<'a> PortMidiTransport<'a> {
+    pub fn new(input: pm::InputPort<'a>, output: pm::OutputPort<'a>) -> PortMidiTransport<'a> {
+        PortMidiTransport {
+            input,
+            output,
+            frames: FrameQueue::new(),
+        }
+    }
+
+    pub fn open(
+        context: &'a pm::PortMidi,
+        input: pm::DeviceInfo,
+        output: pm::DeviceInfo,
+    ) -> Result<PortMidiTransport<'a>, TransportError> {
+        let input = context
+            .input_port(input, BUFFER_SIZE)
+            .map_err(backend_error)?;
+        let output = context
+            .output_port(output, BUFFER_SIZE)
+            .map_err(backend_error)?;
+        Ok(PortMidiTransport::new(input, output))
+    }
+}
+
+impl<'a> Transport for PortMidiTransport<'a> {
+    fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        self.output.write_sysex(0, message).map_err(backend_error)
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        let input = &self.input;
+        self.frames.receive(timeout, |frames, remaining| {
+            match input.read_n(BUFFER_SIZE).map_err(backend_error)? {
+                Some(ref events) if !events.is_empty() => {
+                    for event in events {
+                        frames.push(&event_bytes(event.message));
+                    }
+                    Ok(true)
+                }
+                _ => {
+                    thread::sleep(remaining.min(Duration::from_millis(POLL_INTERVAL_MS)));
+                    Ok(false)
+                }
+            }
+        })
+    }
+}
+
+// Every input/output pairing, those with matching names first since that is
+// how interfaces usually present their two halves.
+pub fn port_pairs(
+    context: &pm::PortMidi,
+) -> Result<Vec<(pm::DeviceInfo, pm::DeviceInfo)>, TransportError> {
+    let devices = context.devices().map_err(backend_error)?;
+    let mut pairs = Vec::new();
+    for input in devices.iter().filter(|device| device.is_input()) {
+        for output in devices.iter().filter(|device| device.is_output()) {
+            pairs.push((input.clone(), output.clone()));
+        }
+    }
+    pairs.sort_by_key(|(input, output)| input.name() != output.name());
+    Ok(pairs)
+}
+
+// Pings every port pair and returns those with a bootloader listening.
+pub fn discover(
+    context: &pm::PortMidi,
+    timeout: Duration,
+) -> Result<Vec<(pm::DeviceInfo, pm::DeviceInfo)>, TransportError> {
+    Ok(discovery::discover(
+        port_pairs(context)?,
+        timeout,
+        |(input, output)| PortMidiTransport::open(context, input.clone(), output.clone()),
+    ))
+}
+
+pub fn backend_error(error: pm::Error) -> TransportError {
+    TransportError::Backend(error.to_string())
+}
+
+// portmidi packs sysex into four bytes per event and delivers realtime
+// messages as separate events, even in the middle of a sysex.
+fn event_bytes(message: pm::MidiMessage) -> Vec<u8> {
+    let bytes = [message.status, message.data1, message.data2, message.data3];
+    if bytes[0] >= 0xf8 {
+        return vec![bytes[0]];
+    }
+    match bytes.iter().position(|&byte| byte == FOOTER[0]) {
+        Some(end) => bytes[..end + 1].to_vec(),
+        None => bytes.to_vec(),
+    }
+}
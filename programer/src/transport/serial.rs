@@ -0,0 +1,232 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+use libc;
+
+use error::TransportError;
+use transport::{FrameQueue, Transport};
+
+pub const BAUD_RATE: u32 = 31250;
+
+const BUFFER_SIZE: usize = 1024;
+
+// Talks to the bootloader's UART directly, e.g. through a USB-UART cable on
+// the MIDI pins. 31250 baud is not a standard termios rate, so the port is
+// configured through termios2 with BOTHER.
+pub struct SerialTransport {
+    port: File,
+    frames: FrameQueue,
+}
+
+impl SerialTransport {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SerialTransport, TransportError> {
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(path)?;
+        SerialTransport::from_file(port)
+    }
+
+    pub fn from_file(port: File) -> Result<SerialTransport, TransportError> {
+        configure(&port, BAUD_RATE)?;
+        // Reads never block with VMIN = VTIME = 0, but writes should.
+        let fd = port.as_raw_fd();
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags == -1 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) == -1 {
+                return Err(TransportError::Io(io::Error::last_os_error()));
+            }
+        }
+        Ok(SerialTransport {
+            port,
+            frames: FrameQueue::new(),
+        })
+    }
+}
+
+impl Transport for SerialTransport {
+    fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        self.port.write_all(message)?;
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        let mut port = &self.port;
+        let mut buffer = [0; BUFFER_SIZE];
+        self.frames.receive(timeout, |frames, remaining| {
+            if !wait_readable(port, remaining)? {
+                return Ok(false);
+            }
+            match port.read(&mut buffer) {
+                // Readable but empty only after a hangup, e.g. when the
+                // interface is unplugged.
+                Ok(0) => Err(TransportError::Io(io::ErrorKind::UnexpectedEof.into())),
+                Ok(count) => {
+                    frames.push(&buffer[..count]);
+                    Ok(true)
+                }
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                Err(error) => Err(TransportError::Io(error)),
+            }
+        })
+    }
+}
+
+fn wait_readable(port: &File, timeout: Duration) -> io::Result<bool> {
+    let mut fds = libc::pollfd {
+        fd: port.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let ready = unsafe { libc::poll(&mut fds, 1, timeout_ms) };
+    match ready {
+        -1 => {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(error)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+// Raw 8N1 at a custom baud rate, reads returning whatever is available.
+fn configure(port: &File, baud_rate: u32) -> io::Result<()> {
+    let fd = port.as_raw_fd();
+    let mut tio: libc::termios2 = unsafe { mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TCGETS2, &mut tio) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    tio.c_iflag &= !(libc::IGNBRK
+        | libc::BRKINT
+        | libc::PARMRK
+        | libc::ISTRIP
+        | libc::INLCR
+        | libc::IGNCR
+        | libc::ICRNL
+        | libc::IXON
+        | libc::IXOFF
+        | libc::IXANY);
+    tio.c_oflag &= !libc::OPOST;
+    tio.c_lflag &= !(libc::ECHO | libc::ECHONL | libc::ICANON | libc::ISIG | libc::IEXTEN);
+    tio.c_cflag &= !(libc::CSIZE
+        | libc::PARENB
+        | libc::CSTOPB
+        | libc::CRTSCTS
+        | libc::CBAUD
+        | libc::CBAUD << libc::IBSHIFT);
+    tio.c_cflag |=
+        libc::CS8 | libc::CREAD | libc::CLOCAL | libc::BOTHER | libc::BOTHER << libc::IBSHIFT;
+    tio.c_ispeed = baud_rate;
+    tio.c_ospeed = baud_rate;
+    tio.c_cc[libc::VMIN] = 0;
+    tio.c_cc[libc::VTIME] = 0;
+
+    if unsafe { libc::ioctl(fd, libc::TCSETS2, &tio) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::FromRawFd;
+    use std::ptr;
+    use std::thread;
+    use std::time::Instant;
+
+    use command::Command;
+    use reply::Reply;
+    use session::Session;
+    use target::ATMEGA16;
+    use transport::simulator::Simulator;
+
+    // The master end stands in for the board, the slave end for the cable.
+    fn pty() -> (File, File) {
+        let (mut master, mut slave) = (0, 0);
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+            )
+        };
+        assert_eq!(result, 0, "{}", io::Error::last_os_error());
+        unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) }
+    }
+
+    #[test]
+    fn zero_timeout_reads_what_has_arrived() {
+        let (mut board, cable) = pty();
+        let mut transport = SerialTransport::from_file(cable).unwrap();
+        let reply = Reply::Success.to_sysex();
+        board.write_all(&reply).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(transport.receive(Duration::from_millis(0)).unwrap(), reply);
+        assert!(matches!(
+            transport.receive(Duration::from_millis(0)),
+            Err(TransportError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn hangup_is_an_error() {
+        let (board, cable) = pty();
+        let mut transport = SerialTransport::from_file(cable).unwrap();
+        drop(board);
+        let start = Instant::now();
+        assert!(matches!(
+            transport.receive(Duration::from_millis(200)),
+            Err(TransportError::Io(_))
+        ));
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn stale_reply_is_not_taken_for_the_next() {
+        let (mut board, cable) = pty();
+        let mut transport = SerialTransport::from_file(cable).unwrap();
+
+        // A late answer to an earlier read, already waiting in the tty.
+        let stale = Reply::Read(vec![0x00; ATMEGA16.page_size]).to_sysex();
+        board.write_all(&stale).unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        let mut simulator = Simulator::new(ATMEGA16);
+        for byte in &mut simulator.flash_mut()[..ATMEGA16.page_size] {
+            *byte = 0x42;
+        }
+        let bootloader = thread::spawn(move || {
+            let mut buffer = [0; BUFFER_SIZE];
+            // Ends with EIO once the cable end is closed.
+            while let Ok(count) = board.read(&mut buffer) {
+                if count == 0 {
+                    break;
+                }
+                simulator.send(&buffer[..count]).unwrap();
+                while let Ok(frame) = simulator.receive(Duration::from_millis(0)) {
+                    board.write_all(&frame).unwrap();
+                }
+            }
+        });
+
+        let mut session = Session::new(&mut transport, ATMEGA16);
+        assert_eq!(session.read(0).unwrap(), vec![0x42; ATMEGA16.page_size]);
+        drop(transport);
+        bootloader.join().unwrap();
+    }
+}
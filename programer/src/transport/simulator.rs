@@ -0,0 +1,459 @@
+use std::time::Duration;
+
+use command::{
+    self, Command, COMMAND_PING, COMMAND_QUIT, COMMAND_READ, COMMAND_VERIFY, COMMAND_VERIFY_RANGE,
+    COMMAND_WRITE, FOOTER, HEADER,
+};
+use error::{BootloaderError, TransportError};
+use image::ERASED;
+use protocol::{Encoding, Integrity, Protocol, PROTOCOL_V1};
+use reply::Reply;
+use target::Target;
+use transport::{FrameQueue, Transport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum State {
+    Idle,
+    MatchingHeader,
+    ReadingBody,
+    ExpectingEnd,
+}
+
+// An in-process model of firmware/bootloader.cpp: the same receive state
+// machine, error codes and replies, on top of a flash array that is erased
+// page-wise before programming. Like the real bootloader it speaks VERSION
+// 0x01 only, unless told to model one of the draft versions.
+pub struct Simulator {
+    target: Target,
+    protocols: Vec<Protocol>,
+    flash: Vec<u8>,
+    state: State,
+    protocol: Protocol,
+    buffer: Vec<u8>,
+    bytes_read: usize,
+    pending: u8,
+    payload_size: usize,
+    checksum: u8,
+    running_application: bool,
+    output: FrameQueue,
+}
+
+impl Simulator {
+    pub fn new(target: Target) -> Simulator {
+        Simulator {
+            target,
+            protocols: vec![PROTOCOL_V1],
+            flash: vec![ERASED; target.flash_size],
+            state: State::Idle,
+            protocol: PROTOCOL_V1,
+            buffer: vec![0; target.page_size + 4],
+            bytes_read: 0,
+            pending: 0,
+            payload_size: 0,
+            checksum: 0,
+            running_application: false,
+            output: FrameQueue::new(),
+        }
+    }
+
+    // Messages in other versions are answered with a header mismatch, sent
+    // in the oldest version supported as the message's own is unknown.
+    pub fn with_protocols(mut self, protocols: &[Protocol]) -> Simulator {
+        assert!(
+            !protocols.is_empty(),
+            "a bootloader speaks at least one protocol"
+        );
+        self.protocols = protocols.to_vec();
+        self.protocols.sort_by_key(|protocol| protocol.version);
+        self.protocol = self.protocols[0];
+        self
+    }
+
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    pub fn flash_mut(&mut self) -> &mut [u8] {
+        &mut self.flash
+    }
+
+    pub fn is_running_application(&self) -> bool {
+        self.running_application
+    }
+
+    // Power cycles the board with the bootloader pins held.
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+        self.running_application = false;
+        self.output = FrameQueue::new();
+    }
+
+    pub fn receive_byte(&mut self, byte: u8) {
+        if self.running_application {
+            return;
+        }
+
+        if byte < 0x80 {
+            match self.state {
+                State::Idle => {}
+                State::MatchingHeader => {
+                    let index = 1 + self.bytes_read;
+                    self.bytes_read += 1;
+                    let matched = if index < HEADER.len() - 1 {
+                        byte == HEADER[index]
+                    } else {
+                        match self
+                            .protocols
+                            .iter()
+                            .find(|protocol| protocol.version == byte)
+                        {
+                            Some(&protocol) => {
+                                self.protocol = protocol;
+                                true
+                            }
+                            None => false,
+                        }
+                    };
+                    if !matched {
+                        self.reply_error(BootloaderError::HeaderMismatch);
+                        self.state = State::Idle;
+                    } else if self.bytes_read == HEADER.len() - 1 {
+                        self.state = State::ReadingBody;
+                        self.bytes_read = 0;
+                    }
+                }
+                State::ReadingBody => match self.protocol.encoding {
+                    Encoding::Nibbles => {
+                        if byte > 0x0f {
+                            self.reply_error(BootloaderError::InvalidNibble);
+                            self.state = State::Idle;
+                            return;
+                        }
+                        if self.bytes_read & 1 == 0 {
+                            self.pending = byte << 4;
+                        } else {
+                            let byte = self.pending | byte;
+                            self.push_payload(byte);
+                        }
+                        self.bytes_read += 1;
+                    }
+                    Encoding::Packed => {
+                        let position = self.bytes_read % 8;
+                        if position == 0 {
+                            self.pending = byte;
+                        } else {
+                            let high_bit = (self.pending >> (position - 1) & 1) << 7;
+                            self.push_payload(byte | high_bit);
+                        }
+                        self.bytes_read += 1;
+                    }
+                },
+                State::ExpectingEnd => {
+                    self.reply_error(BootloaderError::InvalidPayloadSize);
+                    self.state = State::Idle;
+                }
+            }
+        } else if byte == HEADER[0] {
+            if self.state != State::Idle {
+                self.reply_error(BootloaderError::IncompleteMessage);
+            }
+            self.state = State::MatchingHeader;
+            self.protocol = self.protocols[0];
+            self.checksum = 0;
+            self.bytes_read = 0;
+            self.payload_size = 0;
+        } else if byte == FOOTER[0] && self.state != State::Idle {
+            // A packed group needs at least one byte after its top bits.
+            let dangling = self.protocol.encoding == Encoding::Packed && self.bytes_read % 8 == 1;
+            let size = self.protocol.integrity.size();
+            if self.state < State::ReadingBody || self.payload_size <= size || dangling {
+                self.reply_error(BootloaderError::InvalidFormat);
+            } else if !self.is_checksum_valid() {
+                self.reply_error(BootloaderError::InvalidChecksum);
+            } else {
+                self.payload_size -= 1 + size;
+                self.process_msg();
+            }
+            self.state = State::Idle;
+        }
+    }
+
+    fn push_payload(&mut self, byte: u8) {
+        self.buffer[self.payload_size] = byte;
+        self.checksum ^= byte;
+        self.payload_size += 1;
+        // Command, page number, page data and checksum.
+        let capacity = self.target.page_size + 2 + self.protocol.integrity.size();
+        if self.payload_size == capacity {
+            self.state = State::ExpectingEnd;
+        }
+    }
+
+    fn is_checksum_valid(&self) -> bool {
+        match self.protocol.integrity {
+            Integrity::Xor => self.checksum == 0,
+            Integrity::Crc16 => {
+                let (message, crc) =
+                    self.buffer[..self.payload_size].split_at(self.payload_size - 2);
+                command::crc16(message) == u16::from(crc[0]) << 8 | u16::from(crc[1])
+            }
+        }
+    }
+
+    fn process_msg(&mut self) {
+        let page_size = self.target.page_size;
+        let page_no = self.buffer[1] as usize;
+        let page_ok = page_no < self.target.num_pages();
+        let page = page_no * page_size..(page_no + 1) * page_size;
+
+        match self.buffer[0] {
+            COMMAND_PING if self.payload_size == 0 => self.reply(Reply::Success),
+            COMMAND_WRITE if self.payload_size == page_size + 1 => {
+                if !page_ok {
+                    return self.reply_error(BootloaderError::InvalidPageNumber);
+                }
+                for byte in &mut self.flash[page.clone()] {
+                    *byte = ERASED;
+                }
+                for (byte, &data) in self.flash[page].iter_mut().zip(&self.buffer[2..]) {
+                    *byte &= data;
+                }
+                self.reply(Reply::Success);
+            }
+            COMMAND_VERIFY if self.payload_size == 1 => {
+                if !page_ok {
+                    return self.reply_error(BootloaderError::InvalidPageNumber);
+                }
+                let checksum = self.protocol.integrity.checksum(&self.flash[page]);
+                self.reply(Reply::Verify(checksum));
+            }
+            COMMAND_READ if self.payload_size == 1 => {
+                if !page_ok {
+                    return self.reply_error(BootloaderError::InvalidPageNumber);
+                }
+                let page_data = self.flash[page].to_vec();
+                self.reply(Reply::Read(page_data));
+            }
+            COMMAND_VERIFY_RANGE if self.protocol.verify_range && self.payload_size == 2 => {
+                let last_page = self.buffer[2] as usize;
+                if !page_ok || last_page < page_no || last_page >= self.target.num_pages() {
+                    return self.reply_error(BootloaderError::InvalidPageNumber);
+                }
+                let range = page_no * page_size..(last_page + 1) * page_size;
+                let checksum = self.protocol.integrity.checksum(&self.flash[range]);
+                self.reply(Reply::Verify(checksum));
+            }
+            COMMAND_QUIT if self.payload_size == 0 => {
+                self.reply(Reply::Success);
+                self.running_application = true;
+            }
+            COMMAND_VERIFY_RANGE if self.protocol.verify_range => {
+                self.reply_error(BootloaderError::InvalidPayloadSize)
+            }
+            COMMAND_PING | COMMAND_WRITE | COMMAND_VERIFY | COMMAND_READ | COMMAND_QUIT => {
+                self.reply_error(BootloaderError::InvalidPayloadSize)
+            }
+            _ => self.reply_error(BootloaderError::UnknownCommand),
+        }
+    }
+
+    fn reply(&mut self, reply: Reply) {
+        self.output.push(&reply.to_sysex_with(&self.protocol));
+    }
+
+    fn reply_error(&mut self, error: BootloaderError) {
+        self.reply(Reply::Error(error));
+    }
+}
+
+impl Transport for Simulator {
+    fn send(&mut self, message: &[u8]) -> Result<(), TransportError> {
+        for &byte in message {
+            self.receive_byte(byte);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, _timeout: Duration) -> Result<Vec<u8>, TransportError> {
+        self.output.pop().unwrap_or(Err(TransportError::Timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Ping, Read, Write};
+    use programmer::Programmer;
+    use protocol::PROTOCOLS;
+    use session::Session;
+    use target::ATMEGA16;
+    use testing;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        testing::frame(&PROTOCOL_V1, payload)
+    }
+
+    fn replies(simulator: &mut Simulator, message: &[u8]) -> Vec<Reply> {
+        simulator.send(message).unwrap();
+        let mut replies = Vec::new();
+        while let Ok(frame) = simulator.receive(Duration::from_millis(0)) {
+            replies.push(Reply::from_sysex(&frame).unwrap());
+        }
+        replies
+    }
+
+    fn assert_error(message: &[u8], error: BootloaderError) {
+        let mut simulator = Simulator::new(ATMEGA16);
+        assert_eq!(replies(&mut simulator, message), vec![Reply::Error(error)]);
+    }
+
+    #[test]
+    fn flash_read_and_verify_round_trip() {
+        let image = testing::test_image(10 * ATMEGA16.page_size + 17);
+        for protocol in PROTOCOLS.iter() {
+            let mut simulator = Simulator::new(ATMEGA16).with_protocols(&PROTOCOLS);
+            {
+                let session = Session::new(&mut simulator, ATMEGA16).with_protocol(*protocol);
+                let report = Programmer::new(session).flash(&image).unwrap();
+                assert!(report.is_success());
+                assert_eq!(report.written.len(), 11);
+            }
+            assert!(simulator.is_running_application());
+            for (address, byte) in image.iter() {
+                assert_eq!(simulator.flash()[address], byte);
+            }
+            assert_eq!(simulator.flash()[11 * ATMEGA16.page_size], ERASED);
+
+            simulator.reset();
+            let session = Session::new(&mut simulator, ATMEGA16).with_protocol(*protocol);
+            let mut programmer = Programmer::new(session);
+            assert!(programmer.verify(&image).unwrap().is_success());
+            let dump = programmer.read_flash(false).unwrap();
+            for page_no in 0..ATMEGA16.boot_page() {
+                assert_eq!(
+                    dump.page(&ATMEGA16, page_no),
+                    image.page(&ATMEGA16, page_no)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn write_erases_the_page_first() {
+        let mut simulator = Simulator::new(ATMEGA16);
+        for &fill in &[0x0f, 0xf0] {
+            let write = Write::new(&ATMEGA16, 3, vec![fill; ATMEGA16.page_size]).unwrap();
+            assert_eq!(
+                replies(&mut simulator, &write.to_sysex()),
+                vec![Reply::Success]
+            );
+        }
+        let read = Read::new(&ATMEGA16, 3).unwrap();
+        assert_eq!(
+            replies(&mut simulator, &read.to_sysex()),
+            vec![Reply::Read(vec![0xf0; ATMEGA16.page_size])]
+        );
+    }
+
+    #[test]
+    fn header_mismatch() {
+        assert_error(
+            &[0xf0, 0x00, 0x71, 0x01, 0x01, 0x00, 0x01, 0x00, 0xf7],
+            BootloaderError::HeaderMismatch,
+        );
+        let mut ping = Ping {}.to_sysex();
+        ping[3] = 0x02;
+        assert_error(&ping, BootloaderError::HeaderMismatch);
+    }
+
+    #[test]
+    fn invalid_format() {
+        assert_error(&[0xf0, 0x00, 0x70, 0xf7], BootloaderError::InvalidFormat);
+        assert_error(
+            &[0xf0, 0x00, 0x70, 0x01, 0xf7],
+            BootloaderError::InvalidFormat,
+        );
+        assert_error(&frame(&[]), BootloaderError::InvalidFormat);
+    }
+
+    #[test]
+    fn incomplete_message() {
+        let mut simulator = Simulator::new(ATMEGA16);
+        let mut message = Ping {}.to_sysex();
+        message.truncate(6);
+        message.extend(Ping {}.to_sysex());
+        assert_eq!(
+            replies(&mut simulator, &message),
+            vec![
+                Reply::Error(BootloaderError::IncompleteMessage),
+                Reply::Success
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_nibble() {
+        let mut ping = Ping {}.to_sysex();
+        ping[4] = 0x11;
+        assert_error(&ping, BootloaderError::InvalidNibble);
+    }
+
+    #[test]
+    fn invalid_checksum() {
+        let mut ping = Ping {}.to_sysex();
+        ping[7] ^= 0x01;
+        assert_error(&ping, BootloaderError::InvalidChecksum);
+    }
+
+    #[test]
+    fn unknown_command() {
+        assert_error(&frame(&[0x30]), BootloaderError::UnknownCommand);
+        assert_error(
+            &frame(&[COMMAND_VERIFY_RANGE, 0, 1]),
+            BootloaderError::UnknownCommand,
+        );
+    }
+
+    #[test]
+    fn invalid_payload_size() {
+        assert_error(
+            &frame(&[COMMAND_PING, 0]),
+            BootloaderError::InvalidPayloadSize,
+        );
+        assert_error(&frame(&[COMMAND_READ]), BootloaderError::InvalidPayloadSize);
+        assert_error(
+            &frame(&[COMMAND_WRITE, 0, 0xff]),
+            BootloaderError::InvalidPayloadSize,
+        );
+        let mut overlong = vec![COMMAND_WRITE, 0];
+        overlong.extend(vec![0; ATMEGA16.page_size + 1]);
+        assert_error(&frame(&overlong), BootloaderError::InvalidPayloadSize);
+    }
+
+    #[test]
+    fn invalid_page_number() {
+        let page_no = ATMEGA16.num_pages() as u8;
+        assert_error(
+            &frame(&[COMMAND_READ, page_no]),
+            BootloaderError::InvalidPageNumber,
+        );
+        assert_error(
+            &frame(&[COMMAND_VERIFY, page_no]),
+            BootloaderError::InvalidPageNumber,
+        );
+        let mut write = vec![COMMAND_WRITE, page_no];
+        write.extend(vec![0; ATMEGA16.page_size]);
+        assert_error(&frame(&write), BootloaderError::InvalidPageNumber);
+    }
+
+    #[test]
+    fn quit_starts_the_application() {
+        let mut simulator = Simulator::new(ATMEGA16);
+        assert_eq!(
+            replies(&mut simulator, &frame(&[COMMAND_QUIT])),
+            vec![Reply::Success]
+        );
+        assert!(simulator.is_running_application());
+        assert_eq!(replies(&mut simulator, &Ping {}.to_sysex()), vec![]);
+    }
+}